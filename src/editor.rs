@@ -1,60 +1,185 @@
 use super::terminal::settings::Window;
 use super::*;
 use highlight::{HLGroup, SyntaxHighlight};
+use history::{Change, Edit};
 use modes::normal_mode::QuitError;
+use std::collections::VecDeque;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Duration;
 use terminal::display::TermBuffer;
+use terminal::input::{Key, KeySource, TerminalInput};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+mod buffer;
+pub mod config;
+mod ex;
 pub mod highlight;
+mod history;
+mod layout;
+mod mappings;
 mod modes;
+mod motions;
+mod operators;
+mod options;
+mod regex;
+mod registers;
+mod repeat;
+mod rope;
 pub mod searching;
+mod substitute;
 
+// A character of the rendered line: its bytes in the render and the screen
+// columns it takes.
+struct RenderedChar {
+    start: usize,
+    end: usize,
+    column: usize,
+    width: usize,
+}
+
+// Rendered form of a line. It is only built for the rows that are drawn
+// and cached until the line is modified.
 struct EditorLine {
-    content: String,
-    render: Vec<u8>,
+    render: String,
+    chars: Vec<RenderedChar>,
+    width: usize,
     highlight: Vec<HLGroup>,
 }
 
 impl EditorLine {
-    fn new(content: &str, syntax_hl: &Option<SyntaxHighlight>) -> Self {
-        let mut render = vec![];
-        for &c in content.as_bytes() {
-            if c == b'\t' {
-                for _ in 0..4 {
-                    render.push(b' ');
-                }
+    fn new(content: &str, syntax_hl: &Option<SyntaxHighlight>, tabstop: usize) -> Self {
+        let mut render = String::new();
+        let mut chars = vec![];
+        let mut column = 0;
+
+        for c in content.graphemes(true) {
+            let width = char_width(c, column, tabstop);
+
+            // A tab is drawn as spaces up to the next tab stop.
+            let (text, width, repeat) = if c == "\t" {
+                (" ", 1, width)
             } else {
-                render.push(c);
+                (c, width, 1)
+            };
+
+            for _ in 0..repeat {
+                chars.push(RenderedChar {
+                    start: render.len(),
+                    end: render.len() + text.len(),
+                    column,
+                    width,
+                });
+                render.push_str(text);
+                column += width;
             }
         }
 
-        let highlight = highlight::get_line_highlighting(&render, syntax_hl);
+        let highlight = highlight::get_line_highlighting(render.as_bytes(), syntax_hl);
 
         Self {
-            content: content.to_string(),
             render,
+            chars,
+            width: column,
             highlight,
         }
     }
+}
 
-    fn map_fx_to_rx(&self, fx: usize) -> usize {
-        self.content
-            .chars()
-            .map(|c| match c {
-                '\t' => 4,
-                _ => 1,
-            })
-            .take(fx)
-            .sum::<usize>()
-            .saturating_sub(1)
+// Position after moving the text from `from` on to `to`, for a position
+// at `from` or after it.
+fn shift_position(pos: (usize, usize), from: (usize, usize), to: (usize, usize)) -> (usize, usize) {
+    match pos.0 == from.0 {
+        true => (to.0, to.1 + pos.1 - from.1),
+        false => (pos.0 + to.0 - from.0, pos.1),
     }
 }
 
+// Moves the view so that the cursor is in the `num_rows` lines and the
+// `num_cols` columns shown.
+fn scroll_to_cursor(cursor: &Cursor, offset: &mut Offset, num_rows: usize, num_cols: usize) {
+    if cursor.fy < offset.y {
+        offset.y = cursor.fy;
+    }
+    if cursor.fy >= offset.y + num_rows {
+        offset.y = cursor.fy - num_rows + 1;
+    }
+    if cursor.rx < offset.x {
+        offset.x = cursor.rx;
+    }
+    if cursor.rx >= offset.x + num_cols {
+        offset.x = cursor.rx - num_cols + 1;
+    }
+}
+
+// Characters are grapheme clusters, like a letter together with its accents,
+// so that the cursor never stands inside one. Their positions are bytes.
+
+// End of the character starting at the byte `x`, or `x` at the end of the
+// line.
+fn char_end(line: &str, x: usize) -> usize {
+    let x = x.min(line.len());
+    line[x..].graphemes(true).next().map_or(x, |c| x + c.len())
+}
+
+// Start of the character ending at the byte `x`, or 0 at the start of the
+// line.
+fn char_start(line: &str, x: usize) -> usize {
+    let x = x.min(line.len());
+    line[..x]
+        .graphemes(true)
+        .next_back()
+        .map_or(0, |c| x - c.len())
+}
+
+// Screen columns taken by a character drawn at `column`. A tab reaches the
+// next tab stop and East Asian wide characters take two.
+fn char_width(c: &str, column: usize, tabstop: usize) -> usize {
+    if c == "\t" {
+        tabstop - column % tabstop
+    } else {
+        c.width().max(1)
+    }
+}
+
+// Render column where the byte `fx` of the line starts.
+fn render_x(content: &str, fx: usize, tabstop: usize) -> usize {
+    content[..fx.min(content.len())]
+        .graphemes(true)
+        .fold(0, |column, c| column + char_width(c, column, tabstop))
+}
+
+// Byte of the line drawn at the render column `rx`, or the length of the
+// line if it is shorter.
+fn render_x_to_fx(content: &str, rx: usize, tabstop: usize) -> usize {
+    let mut x = 0;
+    for (fx, c) in content.grapheme_indices(true) {
+        x += char_width(c, x, tabstop);
+        if x > rx {
+            return fx;
+        }
+    }
+    content.len()
+}
+
+#[derive(PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 enum Mode {
     NORMAL,
     INSERT,
     COMMAND,
+    OPERATOR_PENDING,
+    VISUAL,
+    VISUAL_LINE,
+    VISUAL_BLOCK,
+}
+
+impl Mode {
+    fn is_visual(&self) -> bool {
+        matches!(self, Mode::VISUAL | Mode::VISUAL_LINE | Mode::VISUAL_BLOCK)
+    }
 }
 
 #[derive(Clone)]
@@ -64,34 +189,92 @@ struct Cursor {
     rx: usize, // render x
 }
 
+#[derive(Clone)]
 struct Offset {
     x: usize, // render units
     y: usize,
 }
 
+// Keys typed since `q{register}` started recording a macro.
+struct Recording {
+    register: char,
+    keys: Vec<Key>,
+    // Number of keys recorded before the current command, whose keys are
+    // dropped when it stops the recording.
+    command_start: usize,
+}
+
 pub struct Editor {
-    file_name: String,
+    // Size of the text area of the current window.
     window: Window,
+    // Windows of the current tab page.
+    layout: layout::Layout,
+    // Layouts of the other tab pages, in order, and index of the current
+    // one among all of them.
+    tab_pages: Vec<layout::Layout>,
+    tab: usize,
     cursor: Cursor,
     offset: Offset,
     mode: Mode,
-    lines: Vec<EditorLine>,
+    // The buffer shown, the others being kept in `buffers`.
+    buffer: buffer::Buffer,
+    buffers: Vec<buffer::Buffer>,
+    // Number of the buffer edited before, for CTRL-^.
+    alternate: Option<usize>,
     cmd_message: String,
-    dirty: bool,
+    // Set when a command fails, to report the errors of a virurc.
+    failed: bool,
+    // Number of files being sourced, see config::source.
+    source_depth: usize,
+    // Set while a project `.virurc` runs, the files it sources must be
+    // trusted too.
+    sourcing_project: bool,
     last_pattern: Option<String>,
-    syntax_hl: Option<SyntaxHighlight>,
+    // Compiled last pattern with whether it ignores case, see
+    // searching::last_regex.
+    last_regex: Option<(String, bool, Rc<regex::Regex>)>,
+    // Direction and offset of the last `/` or `?`, followed by `n`.
+    search_forward: bool,
+    search_offset: searching::Offset,
+    // Matches of the last pattern are highlighted until `:nohlsearch`.
+    highlight_matches: bool,
+    // Index of the match at the cursor and number of matches, shown after
+    // a search.
+    search_count: Option<(usize, usize)>,
+    options: options::Options,
+    last_find: Option<motions::Motion>,
+    registers: registers::Registers,
+    last_change: Option<repeat::RepeatableChange>,
+    insert_change: Option<repeat::RepeatableChange>,
+    key_source: Box<dyn KeySource>,
+    mappings: mappings::Mappings,
+    // Keys to process before reading more, with whether mappings apply
+    // to them.
+    typeahead: VecDeque<(Key, bool)>,
+    recording: Option<Recording>,
+    last_macro: Option<char>,
+    // The other end of the selection in visual mode, the cursor being one.
+    visual_start: (usize, usize),
+    block_insert: Option<operators::BlockInsert>,
+    last_substitute: Option<substitute::Substitute>,
+    // Match shown while `:s///c` asks whether to replace it.
+    current_match: Option<(usize, usize, usize)>,
 }
 
 impl Editor {
-    pub fn new(file_path: String) -> io::Result<Self> {
+    pub fn new(file_paths: &[String], virurc: config::Virurc) -> io::Result<Self> {
         let mut window = terminal::settings::get_window_size();
-        window.num_rows -= 2;
-
-        let syntax_hl = highlight::get_syntax_highlighting(&file_path);
+        // The first row is the tabline, the last one the command line and the
+        // one above it the status line.
+        let layout = layout::Layout::new(1, window.num_cols, window.num_rows - 2);
+        window.num_rows -= 3;
+        let options = options::Options::default();
 
-        Ok(Self {
-            file_name: file_path.clone(),
+        let mut editor = Self {
             window,
+            layout,
+            tab_pages: vec![],
+            tab: 0,
             cursor: Cursor {
                 fx: 0,
                 fy: 0,
@@ -99,16 +282,362 @@ impl Editor {
             },
             offset: Offset { x: 0, y: 0 },
             mode: Mode::NORMAL,
-            lines: read_file(&file_path, &syntax_hl)?,
-            cmd_message: "You are a great programmer!".to_string(),
-            dirty: false,
+            buffer: buffer::Buffer::empty(1),
+            buffers: vec![],
+            alternate: None,
+            cmd_message: String::new(),
+            failed: false,
             last_pattern: None,
-            syntax_hl,
-        })
+            last_regex: None,
+            source_depth: 0,
+            sourcing_project: false,
+            search_forward: true,
+            search_offset: searching::Offset::default(),
+            highlight_matches: false,
+            search_count: None,
+            options,
+            last_find: None,
+            registers: registers::Registers::new(),
+            last_change: None,
+            insert_change: None,
+            key_source: Box::<TerminalInput>::default(),
+            mappings: mappings::Mappings::default(),
+            typeahead: VecDeque::new(),
+            recording: None,
+            last_macro: None,
+            visual_start: (0, 0),
+            block_insert: None,
+            last_substitute: None,
+            current_match: None,
+        };
+
+        let errors = config::load(&mut editor, virurc);
+        // The first file is shown, the others wait in the buffer list.
+        editor.open_file(&file_paths[0])?;
+        for file_path in &file_paths[1..] {
+            let number = editor.next_buffer_number();
+            editor
+                .buffers
+                .push(buffer::Buffer::open(number, file_path)?);
+        }
+
+        match errors.len() {
+            0 if !editor.options.shortmess('I') => {
+                editor.cmd_message = "You are a great programmer!".to_string();
+            }
+            0 => {}
+            1 => editor.cmd_message = errors[0].clone(),
+            n => editor.cmd_message = format!("{} (and {} more errors)", errors[0], n - 1),
+        }
+        Ok(editor)
     }
 
-    fn map_fx_to_rx(&self, rx: usize) -> usize {
-        self.lines[self.cursor.fy].map_fx_to_rx(rx)
+    // Starts editing a file, e.g. with `:e`. A buffer already editing it
+    // is shown again, and the empty buffer present at startup is reused.
+    fn open_file(&mut self, file_path: &str) -> io::Result<()> {
+        if let Some(number) = self.find_buffer(file_path) {
+            self.switch_to_buffer(number);
+            return Ok(());
+        }
+
+        let unused = self.buffer.file_name.is_empty()
+            && !self.buffer.dirty
+            && !self.shown_elsewhere(self.buffer.number);
+        let number = match unused {
+            true => self.buffer.number,
+            false => self.next_buffer_number(),
+        };
+        let buffer = buffer::Buffer::open(number, file_path)?;
+
+        if unused {
+            self.buffer = buffer;
+            self.cursor = Cursor {
+                fx: 0,
+                fy: 0,
+                rx: 0,
+            };
+            self.offset = Offset { x: 0, y: 0 };
+        } else {
+            self.buffers.push(buffer);
+            self.switch_to_buffer(number);
+        }
+        Ok(())
+    }
+
+    // Reads the file of the buffer again, dropping its changes.
+    fn reload_buffer(&mut self) -> io::Result<()> {
+        self.buffer = buffer::Buffer::open(self.buffer.number, &self.buffer.file_name)?;
+        self.clamp_cursor();
+        Ok(())
+    }
+
+    fn next_buffer_number(&self) -> usize {
+        self.buffers
+            .iter()
+            .map(|buffer| buffer.number)
+            .chain(std::iter::once(self.buffer.number))
+            .max()
+            .unwrap()
+            + 1
+    }
+
+    fn find_buffer(&self, file_path: &str) -> Option<usize> {
+        std::iter::once(&self.buffer)
+            .chain(&self.buffers)
+            .find(|buffer| !buffer.file_name.is_empty() && buffer.file_name == file_path)
+            .map(|buffer| buffer.number)
+    }
+
+    // Numbers of all the buffers, in order.
+    fn buffer_numbers(&self) -> Vec<usize> {
+        let mut numbers: Vec<usize> = self.buffers.iter().map(|buffer| buffer.number).collect();
+        numbers.push(self.buffer.number);
+        numbers.sort();
+        numbers
+    }
+
+    fn buffer_in(&self, number: usize) -> &buffer::Buffer {
+        std::iter::once(&self.buffer)
+            .chain(&self.buffers)
+            .find(|buffer| buffer.number == number)
+            .unwrap()
+    }
+
+    fn buffer_in_mut(&mut self, number: usize) -> &mut buffer::Buffer {
+        std::iter::once(&mut self.buffer)
+            .chain(&mut self.buffers)
+            .find(|buffer| buffer.number == number)
+            .unwrap()
+    }
+
+    // Shows another buffer in the window. The one left becomes the
+    // alternate buffer.
+    fn switch_to_buffer(&mut self, number: usize) {
+        if !self.buffers.iter().any(|buffer| buffer.number == number) {
+            return;
+        }
+
+        self.buffer.last_cursor = self.cursor.clone();
+        self.buffer.last_offset = self.offset.clone();
+        self.alternate = Some(self.buffer.number);
+        self.load_buffer(number);
+
+        self.cursor = self.buffer.last_cursor.clone();
+        self.offset = self.buffer.last_offset.clone();
+        self.clamp_cursor();
+    }
+
+    // Makes a buffer the current one, together with its local options.
+    fn load_buffer(&mut self, number: usize) {
+        let i = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.number == number)
+            .unwrap();
+        let mut buffer = self.buffers.remove(i);
+        let mut options = buffer
+            .options
+            .take()
+            .unwrap_or_else(|| self.options.new_local());
+        self.options.swap_local(&mut options);
+        let mut old = std::mem::replace(&mut self.buffer, buffer);
+        old.options = Some(options);
+        self.buffers.push(old);
+
+        // Options changed while the buffer was hidden may render it anew.
+        self.buffer.render_cache.clear();
+    }
+
+    // Keeps the cursor in the text, e.g. after it changed in another window.
+    fn clamp_cursor(&mut self) {
+        self.cursor.fy = self.cursor.fy.min(self.buffer.text.num_lines() - 1);
+        modes::normal_mode::normalize_fx(self);
+    }
+
+    fn current_view(&self) -> layout::View {
+        layout::View {
+            id: self.layout.current,
+            buffer: self.buffer.number,
+            cursor: self.cursor.clone(),
+            offset: self.offset.clone(),
+        }
+    }
+
+    // Makes the window of the view the current one.
+    fn enter_view(&mut self, view: layout::View) {
+        self.layout.current = view.id;
+        if view.buffer != self.buffer.number {
+            self.load_buffer(view.buffer);
+        }
+        self.cursor = view.cursor;
+        self.offset = view.offset;
+        self.update_window_size();
+        self.clamp_cursor();
+    }
+
+    fn update_window_size(&mut self) {
+        let rect = self.layout.rect(self.layout.current);
+        self.window.num_rows = rect.height - 1;
+        self.window.num_cols = rect.width;
+    }
+
+    fn goto_window(&mut self, id: usize) {
+        if id == self.layout.current {
+            return;
+        }
+        let i = self
+            .layout
+            .views
+            .iter()
+            .position(|view| view.id == id)
+            .unwrap();
+        let view = self.layout.views.remove(i);
+        self.layout.views.push(self.current_view());
+        self.enter_view(view);
+    }
+
+    // Splits the current window, the new one showing the same part of the
+    // buffer and becoming the current one.
+    fn split_window(&mut self, vertical: bool) -> Result<(), String> {
+        let new = self.layout.split(vertical)?;
+        self.layout.views.push(self.current_view());
+        self.layout.current = new;
+        self.update_window_size();
+        Ok(())
+    }
+
+    // Removes a window other than the last one. Closing the current window
+    // moves to the one taking its place.
+    fn close_window(&mut self, id: usize) {
+        if id != self.layout.current {
+            self.layout.close(id);
+            self.update_window_size();
+            return;
+        }
+
+        let rect = self.layout.rect(id);
+        self.buffer.last_cursor = self.cursor.clone();
+        self.buffer.last_offset = self.offset.clone();
+        self.layout.close(id);
+
+        let (next, _) = self
+            .layout
+            .rects()
+            .into_iter()
+            .find(|(_, other)| {
+                (other.x..=other.x + other.width).contains(&rect.x)
+                    && (other.y..other.y + other.height).contains(&rect.y)
+            })
+            .unwrap();
+        let i = self
+            .layout
+            .views
+            .iter()
+            .position(|view| view.id == next)
+            .unwrap();
+        let view = self.layout.views.remove(i);
+        self.enter_view(view);
+    }
+
+    // Whether a window other than the current one, in any tab page, shows
+    // the buffer.
+    fn shown_elsewhere(&self, number: usize) -> bool {
+        std::iter::once(&self.layout)
+            .chain(&self.tab_pages)
+            .flat_map(|layout| &layout.views)
+            .any(|view| view.buffer == number)
+    }
+
+    // Layouts of all the tab pages in order, `None` standing for the
+    // current one.
+    fn tab_layouts(&self) -> impl Iterator<Item = Option<&layout::Layout>> {
+        let (before, after) = self.tab_pages.split_at(self.tab);
+        let before = before.iter().map(Some);
+        before
+            .chain(std::iter::once(None))
+            .chain(after.iter().map(Some))
+    }
+
+    fn num_tabs(&self) -> usize {
+        self.tab_pages.len() + 1
+    }
+
+    // Enters the current window of the layout, once taken out of
+    // `tab_pages`.
+    fn enter_tab(&mut self) {
+        let i = self
+            .layout
+            .views
+            .iter()
+            .position(|view| view.id == self.layout.current)
+            .unwrap();
+        let view = self.layout.views.remove(i);
+        self.enter_view(view);
+    }
+
+    // Shows the tab page with the index among all of them.
+    fn goto_tab(&mut self, i: usize) {
+        if i == self.tab {
+            return;
+        }
+        self.layout.views.push(self.current_view());
+        let layout = self.tab_pages.remove(if i > self.tab { i - 1 } else { i });
+        let old = std::mem::replace(&mut self.layout, layout);
+        self.tab_pages
+            .insert(if i > self.tab { self.tab } else { self.tab - 1 }, old);
+        self.tab = i;
+        self.enter_tab();
+    }
+
+    // Opens a tab page after the current one, with a window on a new empty
+    // buffer.
+    fn new_tab(&mut self) {
+        self.layout.views.push(self.current_view());
+        let layout = layout::Layout::new(self.layout.top, self.layout.width, self.layout.height);
+        let old = std::mem::replace(&mut self.layout, layout);
+        self.tab_pages.insert(self.tab, old);
+        self.tab += 1;
+
+        let number = self.next_buffer_number();
+        self.buffers.push(buffer::Buffer::empty(number));
+        self.switch_to_buffer(number);
+        self.update_window_size();
+    }
+
+    // Closes the current tab page, which is not the last one. The next tab
+    // page is shown, or the previous one for the last.
+    fn close_tab(&mut self) {
+        self.buffer.last_cursor = self.cursor.clone();
+        self.buffer.last_offset = self.offset.clone();
+        if self.tab == self.tab_pages.len() {
+            self.tab -= 1;
+        }
+        self.layout = self.tab_pages.remove(self.tab);
+        self.enter_tab();
+    }
+
+    // Sets the number of lines, when not `vertical`, or of columns of the
+    // current window.
+    fn resize_window(&mut self, vertical: bool, size: usize) {
+        let size = match vertical {
+            true => size,
+            false => size.saturating_add(1),
+        };
+        self.layout.resize(vertical, size);
+        self.update_window_size();
+    }
+
+    // Screen column of the cursor. Outside of insert mode it stands on the
+    // last column of a tab, like in Vim.
+    fn cursor_column(&self) -> usize {
+        let line = self.buffer.text.line(self.cursor.fy);
+        let x = self.cursor.fx.min(line.len());
+
+        if self.mode != Mode::INSERT && line[x..].starts_with('\t') {
+            render_x(&line, char_end(&line, x), self.options.tabstop()) - 1
+        } else {
+            render_x(&line, x, self.options.tabstop())
+        }
     }
 
     pub fn refresh_screen(&mut self) {
@@ -117,121 +646,438 @@ impl Editor {
         term_buf.clear_screen();
 
         self.scroll();
-        self.draw_rows(&mut term_buf);
-        self.draw_status_line(&mut term_buf);
+        for (id, rect) in self.layout.rects() {
+            if id != self.layout.current {
+                self.scroll_view(id, rect);
+            }
+            self.draw_window(&mut term_buf, id, rect);
+        }
+        self.draw_tabline(&mut term_buf);
         self.draw_command_line(&mut term_buf);
 
+        let rect = self.layout.rect(self.layout.current);
         term_buf.move_cursor(
-            self.cursor.fy - self.offset.y + 1,
-            self.map_fx_to_rx(self.cursor.fx + 1) - self.offset.x + 1,
+            rect.y + self.cursor.fy - self.offset.y + 1,
+            rect.x + self.cursor.rx - self.offset.x + 1,
         );
 
         term_buf.flush();
     }
 
     fn scroll(&mut self) {
-        self.cursor.rx = self.map_fx_to_rx(self.cursor.fx);
+        self.cursor.rx = self.cursor_column();
+        scroll_to_cursor(
+            &self.cursor,
+            &mut self.offset,
+            self.window.num_rows,
+            self.window.num_cols,
+        );
+    }
 
-        if self.cursor.fy < self.offset.y {
-            self.offset.y = self.cursor.fy;
-        }
-        if self.cursor.fy >= self.offset.y + self.window.num_rows {
-            self.offset.y = self.cursor.fy - self.window.num_rows + 1;
+    // Keeps the cursor of another window in the text, which may have been
+    // changed from the current one, and visible.
+    fn scroll_view(&mut self, id: usize, rect: layout::Rect) {
+        let view = self.layout.view(id).unwrap();
+        let (number, mut cursor, mut offset) =
+            (view.buffer, view.cursor.clone(), view.offset.clone());
+        let tabstop = self.tabstop_in(number);
+        let text = &self.buffer_in(number).text;
+
+        cursor.fy = cursor.fy.min(text.num_lines() - 1);
+        let line = text.line(cursor.fy);
+        if cursor.fx >= line.len() {
+            cursor.fx = char_start(&line, line.len());
         }
-        if self.cursor.rx < self.offset.x {
-            self.offset.x = self.cursor.rx;
+        cursor.rx = render_x(&line, cursor.fx, tabstop);
+        scroll_to_cursor(&cursor, &mut offset, rect.height - 1, rect.width);
+
+        let view = self
+            .layout
+            .views
+            .iter_mut()
+            .find(|view| view.id == id)
+            .unwrap();
+        view.cursor = cursor;
+        view.offset = offset;
+    }
+
+    fn tabstop_in(&self, number: usize) -> usize {
+        match &self.buffer_in(number).options {
+            Some(options) if number != self.buffer.number => options.tabstop(),
+            _ => self.options.tabstop(),
         }
-        if self.cursor.rx >= self.offset.x + self.window.num_cols {
-            self.offset.x = self.cursor.rx - self.window.num_cols + 1;
+    }
+
+    // Draws the text and the status line of a window, and the separator on
+    // its right.
+    fn draw_window(&mut self, term_buf: &mut TermBuffer, id: usize, rect: layout::Rect) {
+        let current = id == self.layout.current;
+        let (number, line, offset) = match self.layout.view(id) {
+            Some(view) => (view.buffer, view.cursor.fy, view.offset.clone()),
+            None => (self.buffer.number, self.cursor.fy, self.offset.clone()),
+        };
+
+        self.draw_rows(term_buf, rect, number, &offset, current);
+        self.draw_status_line(term_buf, rect, number, line, current);
+
+        if rect.x + rect.width < self.layout.width {
+            term_buf.set_reverse_video(true);
+            for row in rect.y..rect.y + rect.height {
+                term_buf.move_cursor(row + 1, rect.x + rect.width + 1);
+                term_buf.write(b"|");
+            }
+            term_buf.reset_appearance();
         }
     }
 
-    fn draw_rows(&self, term_buf: &mut TermBuffer) {
-        term_buf.move_cursor(1, 1);
+    fn draw_rows(
+        &mut self,
+        term_buf: &mut TermBuffer,
+        rect: layout::Rect,
+        number: usize,
+        offset: &Offset,
+        current: bool,
+    ) {
+        let num_rows = rect.height - 1;
+        let tabstop = self.tabstop_in(number);
 
-        for i in 0..self.window.num_rows {
-            let row = i + self.offset.y;
+        // Rows shown by the other windows on the buffer are kept as well.
+        let visible: Vec<_> = self
+            .layout
+            .rects()
+            .into_iter()
+            .filter_map(|(id, other)| {
+                let (shown, top) = match self.layout.view(id) {
+                    Some(view) => (view.buffer, view.offset.y),
+                    None => (self.buffer.number, self.offset.y),
+                };
+                (shown == number).then(|| top..top + other.height - 1)
+            })
+            .collect();
+        self.buffer_in_mut(number)
+            .render_cache
+            .retain(|row, _| visible.iter().any(|rows| rows.contains(row)));
+
+        let regex = match self.options.hlsearch() && self.highlight_matches {
+            true => searching::last_regex(self),
+            false => None,
+        };
+
+        for i in 0..num_rows {
+            let row = i + offset.y;
+            term_buf.move_cursor(rect.y + i + 1, rect.x + 1);
 
-            if row < self.lines.len() {
-                if self.lines[row].render.len() > self.offset.x {
-                    let l = self.offset.x;
-                    let r = self.window.num_cols.min(self.lines[row].render.len());
+            if row < self.buffer_in(number).text.num_lines() {
+                // The selection is only shown in the current window.
+                let selected = match current {
+                    true => modes::visual_mode::selected_columns(self, row).or_else(|| {
+                        let (y, from, to) = self.current_match?;
+                        let line = self.buffer.text.line(y);
+                        (y == row)
+                            .then(|| (render_x(&line, from, tabstop), render_x(&line, to, tabstop)))
+                    }),
+                    false => None,
+                };
+                let is_selected = |j: usize| selected.is_some_and(|(from, to)| from <= j && j < to);
 
-                    for j in l..r {
-                        term_buf.set_fg_color(highlight::hl_group_to_term_color(
-                            &self.lines[row].highlight[j],
-                        ));
-                        term_buf.write(&self.lines[row].render[j..=j]);
+                let matches: Vec<(usize, usize)> = match &regex {
+                    Some(regex) => {
+                        let line = self.buffer_in(number).text.line(row);
+                        searching::line_matches(&line, regex)
+                            .iter()
+                            .map(|m| {
+                                (
+                                    render_x(&line, m.start, tabstop),
+                                    render_x(&line, m.end, tabstop),
+                                )
+                            })
+                            .collect()
+                    }
+                    None => vec![],
+                };
+                let is_match = |j: usize| matches.iter().any(|&(from, to)| from <= j && j < to);
+
+                let buffer = self.buffer_in_mut(number);
+                let line = buffer.render_cache.entry(row).or_insert_with(|| {
+                    EditorLine::new(&buffer.text.line(row), &buffer.syntax_hl, tabstop)
+                });
+
+                let l = offset.x;
+                let r = l + rect.width;
+                for c in &line.chars {
+                    if c.column + c.width <= l {
+                        continue;
+                    }
+                    if c.column >= r {
+                        break;
                     }
 
-                    term_buf.set_fg_color(0);
+                    let hl_group = if is_match(c.column) {
+                        &HLGroup::SEARCH
+                    } else {
+                        &line.highlight[c.start]
+                    };
+                    term_buf.set_reverse_video(is_selected(c.column));
+                    term_buf.set_fg_color(highlight::hl_group_to_term_color(hl_group));
+                    term_buf.set_bg_color(highlight::hl_group_to_term_bg_color(hl_group));
+
+                    // A wide character cut by the edge of the screen is
+                    // drawn as spaces.
+                    if c.column < l || c.column + c.width > r {
+                        let visible = (c.column + c.width).min(r) - c.column.max(l);
+                        term_buf.write(" ".repeat(visible).as_bytes());
+                    } else {
+                        term_buf.write(&line.render.as_bytes()[c.start..c.end]);
+                    }
+                }
+
+                // A selected line break is drawn as a space.
+                let end = line.width;
+                if is_selected(end) && (l..r).contains(&end) {
+                    term_buf.set_reverse_video(true);
+                    term_buf.write(b" ");
                 }
+
+                term_buf.set_reverse_video(false);
+                term_buf.set_fg_color(0);
+                term_buf.set_bg_color(49);
             } else {
                 term_buf.write(b"~");
             }
-
-            term_buf.write(b"\r\n");
         }
     }
 
-    fn draw_status_line(&self, term_buf: &mut TermBuffer) {
+    fn draw_status_line(
+        &self,
+        term_buf: &mut TermBuffer,
+        rect: layout::Rect,
+        number: usize,
+        line: usize,
+        current: bool,
+    ) {
+        term_buf.move_cursor(rect.y + rect.height, rect.x + 1);
         term_buf.swap_fg_and_bg_colors();
+        // The status lines of the other windows are dimmed.
+        if !current {
+            term_buf.set_fg_color(90);
+        }
 
-        let file_name = &self.file_name;
-        let dirty_status = if self.dirty { "[modified]" } else { "[sync]" };
-        let current_line = (self.cursor.fy + 1).to_string();
-        let num_lines = self.lines.len().to_string();
-        let file_language = if let Some(syntax_hl) = &self.syntax_hl {
+        let buffer = self.buffer_in(number);
+        let file_name = buffer.name();
+        let dirty_status = if buffer.dirty { "[modified]" } else { "[sync]" };
+        let current_line = (line + 1).to_string();
+        let num_lines = buffer.text.num_lines().to_string();
+        let file_language = if let Some(syntax_hl) = &buffer.syntax_hl {
             &syntax_hl.language
         } else {
             ""
         };
 
+        let search_count = match self.search_count {
+            Some((index, total)) if current => {
+                // Counts past the limit are only known to be bigger.
+                let count = |n: usize| match n > searching::MAX_COUNT {
+                    true => format!(">{}", searching::MAX_COUNT),
+                    false => n.to_string(),
+                };
+                format!("[{}/{}] ", count(index), count(total))
+            }
+            _ => String::new(),
+        };
+
         let line = format!(
             "{} {} {:>5$} {}/{}",
             file_name,
             dirty_status,
-            file_language,
+            search_count + file_language,
             current_line,
             num_lines,
-            self.window.num_cols
-                - 4
-                - file_name.len()
-                - dirty_status.len()
-                - current_line.len()
-                - num_lines.len()
+            rect.width.saturating_sub(
+                4 + file_name.len() + dirty_status.len() + current_line.len() + num_lines.len()
+            )
         );
-        term_buf.write(&line.as_bytes().iter().cloned().collect::<Vec<u8>>());
+        let line: String = line.chars().take(rect.width).collect();
+        term_buf.write(line.as_bytes());
 
         term_buf.reset_appearance();
     }
 
+    // Draws a label for each tab page, with the number of windows when
+    // there are several, `+` when a buffer shown is modified and the name
+    // of the buffer in the current window.
+    fn draw_tabline(&self, term_buf: &mut TermBuffer) {
+        term_buf.move_cursor(1, 1);
+        let mut width = 0;
+
+        for (i, layout) in self.tab_layouts().enumerate() {
+            let (num_windows, current, others) = match layout {
+                Some(layout) => (
+                    layout.num_windows(),
+                    layout.view(layout.current).unwrap().buffer,
+                    &layout.views,
+                ),
+                None => (
+                    self.layout.num_windows(),
+                    self.buffer.number,
+                    &self.layout.views,
+                ),
+            };
+            let modified = std::iter::once(current)
+                .chain(others.iter().map(|view| view.buffer))
+                .any(|number| self.buffer_in(number).dirty);
+
+            let count = match num_windows {
+                1 => String::new(),
+                n => n.to_string(),
+            };
+            let flags = count + if modified { "+" } else { "" };
+            let label = match flags.as_str() {
+                "" => format!(" {} ", self.buffer_in(current).name()),
+                flags => format!(" {} {} ", flags, self.buffer_in(current).name()),
+            };
+            let label: String = label.chars().take(self.layout.width - width).collect();
+            width += label.chars().count();
+
+            term_buf.set_reverse_video(i != self.tab);
+            term_buf.write(label.as_bytes());
+        }
+
+        term_buf.set_reverse_video(true);
+        term_buf.write(" ".repeat(self.layout.width - width).as_bytes());
+        term_buf.reset_appearance();
+    }
+
     fn draw_command_line(&self, term_buf: &mut TermBuffer) {
-        let line = format!("{:<1$}", &self.cmd_message, self.window.num_cols);
-        term_buf.write(&line.as_bytes().iter().cloned().collect::<Vec<u8>>());
+        term_buf.move_cursor(self.layout.top + self.layout.height + 1, 1);
+        let line = format!("{:<1$}", &self.cmd_message, self.layout.width);
+        term_buf.write(line.as_bytes());
+    }
+
+    // Keys are taken from the typeahead first (e.g. when a macro is
+    // executed) and then from the key source. Only the latter are recorded.
+    fn read_key(&mut self) -> Key {
+        match self.typeahead.pop_front() {
+            Some((key, _)) => key,
+            None => self.read_typed_key(None).unwrap(),
+        }
+    }
+
+    fn read_typed_key(&mut self, timeout: Option<Duration>) -> Option<Key> {
+        let key = match timeout {
+            Some(timeout) => self.key_source.read_key_timeout(timeout)?,
+            None => self.key_source.read_key(),
+        };
+        if let Some(recording) = &mut self.recording {
+            recording.keys.push(key);
+        }
+        Some(key)
+    }
+
+    fn map_mode(&self) -> mappings::MapMode {
+        match self.mode {
+            Mode::NORMAL => mappings::MapMode::Normal,
+            Mode::INSERT => mappings::MapMode::Insert,
+            Mode::COMMAND => mappings::MapMode::CommandLine,
+            Mode::OPERATOR_PENDING => mappings::MapMode::OperatorPending,
+            _ => mappings::MapMode::Visual,
+        }
+    }
+
+    // Reads the key starting a command, with the mappings of the mode
+    // applied. While the keys could still become a longer mapping, the
+    // next one is waited for up to `timeoutlen`.
+    fn read_mapped_key(&mut self) -> Key {
+        let mode = self.map_mode();
+        let timeout = Duration::from_millis(self.options.timeoutlen() as u64);
+
+        for _ in 0..1000 {
+            if self.typeahead.is_empty() {
+                let key = self.read_typed_key(None).unwrap();
+                self.typeahead.push_back((key, true));
+            }
+
+            let mapping = loop {
+                let keys: Vec<Key> = self
+                    .typeahead
+                    .iter()
+                    .take_while(|&&(_, remap)| remap)
+                    .map(|&(key, _)| key)
+                    .collect();
+                if keys.is_empty() {
+                    break None;
+                }
+
+                let (found, longer) = self.mappings.lookup(mode, &keys);
+                let found = found.cloned();
+                if !longer || keys.len() < self.typeahead.len() {
+                    break found;
+                }
+                match self.read_typed_key(Some(timeout)) {
+                    Some(key) => self.typeahead.push_back((key, true)),
+                    None => break found,
+                }
+            };
+
+            let Some(mapping) = mapping else {
+                return self.read_key();
+            };
+            self.typeahead.drain(..mapping.lhs.len());
+            // In `:map j jzz` the `j` of the right-hand side is not mapped
+            // again.
+            let prefix = match mapping.rhs.starts_with(&mapping.lhs) {
+                true => mapping.lhs.len(),
+                false => 0,
+            };
+            for (i, &key) in mapping.rhs.iter().enumerate().rev() {
+                let remap = !mapping.noremap && i >= prefix;
+                self.typeahead.push_front((key, remap));
+            }
+        }
+
+        self.cmd_message = "Recursive mapping".to_string();
+        self.fail();
+        Key::Escape
+    }
+
+    // Makes the keys be processed before the remaining typeahead. Mappings
+    // apply to them with `remap`.
+    fn feed_keys(&mut self, keys: &[Key], remap: bool) {
+        for &key in keys.iter().rev() {
+            self.typeahead.push_front((key, remap));
+        }
+    }
+
+    // Called when a command fails. The rest of an executed macro is dropped,
+    // which also ends recursive macros.
+    fn fail(&mut self) {
+        self.failed = true;
+        self.typeahead.clear();
     }
 
     pub fn process_key_press(&mut self) -> Result<(), QuitError> {
-        let c = terminal::input::read_key();
+        if let Some(recording) = &mut self.recording {
+            recording.command_start = recording.keys.len();
+        }
+        let c = self.read_mapped_key();
 
         match self.mode {
             Mode::NORMAL => modes::normal_mode::process_key_press(self, c)?,
             Mode::INSERT => modes::insert_mode::process_key_press(self, c),
+            Mode::VISUAL | Mode::VISUAL_LINE | Mode::VISUAL_BLOCK => {
+                modes::visual_mode::process_key_press(self, c)
+            }
             _ => {}
         }
 
         Ok(())
     }
 
-    fn insert_char(&mut self, c: u8) {
+    fn insert_char(&mut self, c: char) {
         let x = self.cursor.fx;
         let y = self.cursor.fy;
 
-        self.lines[y].content.insert(x, c as char);
-        self.lines[y] = self.build_editor_line(&self.lines[y].content);
-        self.cursor.fx += 1;
-
-        self.dirty = true;
+        self.insert_text(y, x, &c.to_string());
+        self.cursor.fx += c.len_utf8();
     }
 
     fn delete_previous_char(&mut self) {
@@ -239,43 +1085,35 @@ impl Editor {
         let y = self.cursor.fy;
         assert!(x > 0);
 
-        self.lines[y].content.remove(x - 1);
-        self.lines[y] = self.build_editor_line(&self.lines[y].content);
-        self.cursor.fx -= 1;
-
-        self.dirty = true;
+        let start = char_start(&self.buffer.text.line(y), x);
+        self.delete_text((y, start), (y, x));
+        self.cursor.fx = start;
     }
 
     fn delete_current_char(&mut self) {
         let x = self.cursor.fx;
         let y = self.cursor.fy;
 
-        if self.lines[y].content.len() == 0 {
+        if self.buffer.text.line_len(y) == 0 {
             return;
         }
 
-        self.lines[y].content.remove(x);
-        self.lines[y] = self.build_editor_line(&self.lines[y].content);
+        self.delete_text((y, x), (y, char_end(&self.buffer.text.line(y), x)));
 
-        if x == self.lines[y].content.len() {
-            self.cursor.fx -= 1;
+        let line = self.buffer.text.line(y);
+        if x > 0 && x == line.len() {
+            self.cursor.fx = char_start(&line, x);
         }
-
-        self.dirty = true;
     }
 
     fn break_line(&mut self) {
         let x = self.cursor.fx;
         let y = self.cursor.fy;
 
-        let new_line = &self.lines[y].content[x..];
-        self.lines.insert(y + 1, self.build_editor_line(new_line));
-        self.lines[y] = self.build_editor_line(&self.lines[y].content[0..x]);
+        self.insert_text(y, x, "\n");
 
         self.cursor.fx = 0;
         self.cursor.fy += 1;
-
-        self.dirty = true;
     }
 
     fn join_lines(&mut self) {
@@ -284,51 +1122,216 @@ impl Editor {
         assert_eq!(x, 0);
         assert!(y > 0);
 
-        self.cursor.fx = self.lines[y - 1].content.len();
+        self.cursor.fx = self.buffer.text.line_len(y - 1);
         self.cursor.fy -= 1;
 
-        let moved = self.lines[y].content.clone();
-        self.lines[y - 1].content.push_str(&moved);
-        self.lines[y - 1] = self.build_editor_line(&self.lines[y - 1].content);
-        self.lines.remove(y);
-
-        self.dirty = true;
+        self.delete_text((y - 1, self.cursor.fx), (y, 0));
     }
 
     fn add_blank_line(&mut self, at: usize) {
-        self.lines.insert(at, self.build_editor_line(""));
+        if at == 0 {
+            self.insert_text(0, 0, "\n");
+        } else {
+            self.insert_text(at - 1, self.buffer.text.line_len(at - 1), "\n");
+        }
     }
 
-    fn build_editor_line(&self, content: &str) -> EditorLine {
-        EditorLine::new(content, &self.syntax_hl)
+    // All modifications of the text go through these two functions, so that
+    // they can be recorded in the history.
+    fn insert_text(&mut self, y: usize, x: usize, text: &str) {
+        let edit = Edit::Insert {
+            y,
+            x,
+            text: text.to_string(),
+        };
+        self.buffer.history.record(edit.clone(), &self.cursor);
+        self.apply_edit(&edit);
+    }
+
+    fn delete_text(&mut self, from: (usize, usize), to: (usize, usize)) -> String {
+        let edit = Edit::Delete {
+            y: from.0,
+            x: from.1,
+            text: self.get_text(from, to),
+        };
+        self.buffer.history.record(edit.clone(), &self.cursor);
+        self.apply_edit(&edit);
+
+        match edit {
+            Edit::Delete { text, .. } => text,
+            _ => unreachable!(),
+        }
     }
 
-    fn save_file(&mut self) {
-        fs::write(
-            &self.file_name,
-            &self
-                .lines
-                .iter()
-                .flat_map(|line| line.content.chars().chain(std::iter::once('\n')))
-                .collect::<String>()
-                .as_bytes(),
+    fn get_text(&self, from: (usize, usize), to: (usize, usize)) -> String {
+        self.buffer.text.slice(
+            self.buffer.text.line_to_byte(from.0) + from.1,
+            self.buffer.text.line_to_byte(to.0) + to.1,
         )
-        .unwrap();
+    }
 
-        self.dirty = false;
+    fn apply_edit(&mut self, edit: &Edit) {
+        let (y, x, inserted, text) = match edit {
+            Edit::Insert { y, x, text } => {
+                let at = self.buffer.text.line_to_byte(*y) + x;
+                self.buffer.text.insert(at, text);
+                (*y, *x, true, text)
+            }
+            Edit::Delete { y, x, text } => {
+                let at = self.buffer.text.line_to_byte(*y) + x;
+                self.buffer.text.remove(at, at + text.len());
+                (*y, *x, false, text)
+            }
+        };
+
+        // The other windows on the buffer, in every tab page, keep showing
+        // the same text.
+        let num_lines = text.matches('\n').count();
+        let last_len = text.len() - text.rfind('\n').map_or(0, |i| i + 1);
+        let end = match num_lines {
+            0 => (y, x + text.len()),
+            _ => (y + num_lines, last_len),
+        };
+        let views = self.layout.views.iter_mut().chain(
+            self.tab_pages
+                .iter_mut()
+                .flat_map(|layout| layout.views.iter_mut()),
+        );
+        for view in views.filter(|view| view.buffer == self.buffer.number) {
+            let cursor = (view.cursor.fy, view.cursor.fx);
+            (view.cursor.fy, view.cursor.fx) = match inserted {
+                true if cursor >= (y, x) => shift_position(cursor, (y, x), end),
+                false if cursor >= end => shift_position(cursor, end, (y, x)),
+                false if cursor > (y, x) => (y, x),
+                _ => cursor,
+            };
+            if view.offset.y > y {
+                view.offset.y = match inserted {
+                    true => view.offset.y + num_lines,
+                    false => view.offset.y.saturating_sub(num_lines).max(y),
+                };
+            }
+        }
+
+        // Lines below the edit may have been shifted, so all of them are
+        // rendered again.
+        self.buffer.render_cache.retain(|&row, _| row < y);
+
+        self.buffer.dirty = true;
     }
-}
 
-fn read_file(file_path: &str, syntax_hl: &Option<SyntaxHighlight>) -> io::Result<Vec<EditorLine>> {
-    let mut res: Vec<EditorLine> = fs::read_to_string(file_path)?
-        .split("\n")
-        .map(|line| EditorLine::new(line, syntax_hl))
-        .collect();
+    // Content of a register, including the read-only ones.
+    fn get_register(&self, name: char) -> Option<registers::Register> {
+        let text = match name {
+            '%' => self.buffer.file_name.clone(),
+            '/' => self.last_pattern.clone()?,
+            _ => return self.registers.get(name),
+        };
+
+        Some(registers::Register {
+            text,
+            kind: registers::RegisterKind::Charwise,
+        })
+    }
 
-    // Files have a dummy new line at the end that should not be showed.
-    // Corner case when we want to use it is an empty file.
-    if res.len() > 1 {
-        res.pop();
+    fn apply_change(&mut self, change: &Change) {
+        for edit in change.edits.iter() {
+            self.apply_edit(edit);
+        }
+        self.cursor = change.cursor_after.clone();
+    }
+
+    fn revert_change(&mut self, change: &Change) {
+        for edit in change.edits.iter().rev() {
+            self.apply_edit(&edit.inverse());
+        }
+        self.cursor = change.cursor_before.clone();
+    }
+
+    fn undo(&mut self) -> bool {
+        if let Some(change) = self.buffer.history.undo() {
+            self.revert_change(&change);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn redo(&mut self) -> bool {
+        if let Some(change) = self.buffer.history.redo() {
+            self.apply_change(&change);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Walks the undo tree to the given state, possibly switching branches.
+    fn goto_undo_state(&mut self, target: usize) {
+        let path = self.buffer.history.path_to(target);
+
+        for _ in 0..path.num_undos {
+            self.undo();
+        }
+        for seq in path.redos {
+            let change = self.buffer.history.redo_to(seq);
+            self.apply_change(&change);
+        }
+        modes::normal_mode::normalize_fx(self);
+
+        self.cmd_message = format!(
+            "At change {} of {}",
+            self.buffer.history.current(),
+            self.buffer.history.num_states() - 1
+        );
+    }
+
+    // Writes the lines from `first` to `last`, both included, to a file.
+    fn write_file(&mut self, file_path: &str, (first, last): (usize, usize)) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(file_path)?);
+        let mut num_bytes = 1;
+
+        if (first, last) == (0, self.buffer.text.num_lines() - 1) {
+            for chunk in self.buffer.text.chunks() {
+                file.write_all(chunk.as_bytes())?;
+                num_bytes += chunk.len();
+            }
+        } else {
+            let from = self.buffer.text.line_to_byte(first);
+            let to = self.buffer.text.line_to_byte(last) + self.buffer.text.line_len(last);
+            let content = self.buffer.text.slice(from, to);
+            file.write_all(content.as_bytes())?;
+            num_bytes += content.len();
+        }
+        file.write_all(b"\n")?;
+        file.flush()?;
+
+        self.cmd_message = format!(
+            "\"{}\" {}L, {}B written",
+            file_path,
+            last - first + 1,
+            num_bytes
+        );
+        Ok(())
+    }
+
+    fn save_file(&mut self) -> io::Result<()> {
+        let file_name = self.buffer.file_name.clone();
+        self.write_file(&file_name, (0, self.buffer.text.num_lines() - 1))?;
+
+        if self
+            .buffer
+            .history
+            .save(
+                &history::undo_file_path(&self.buffer.file_name),
+                buffer::text_hash(&self.buffer.text),
+            )
+            .is_err()
+        {
+            self.cmd_message = "Cannot write undo file".to_string();
+        }
+
+        self.buffer.dirty = false;
+        Ok(())
     }
-    Ok(res)
 }
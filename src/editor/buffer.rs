@@ -0,0 +1,98 @@
+use super::highlight::{self, SyntaxHighlight};
+use super::history::{self, History};
+use super::options::LocalOptions;
+use super::rope::Rope;
+use super::{Cursor, EditorLine, Offset};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+// A file being edited, with the state that goes with it. A buffer that is
+// not shown keeps its changes and where the cursor was.
+pub struct Buffer {
+    // Number shown by `:ls`, given in the order the buffers are created.
+    pub number: usize,
+    pub file_name: String,
+    pub text: Rope,
+    pub dirty: bool,
+    pub syntax_hl: Option<SyntaxHighlight>,
+    pub history: History,
+    pub render_cache: HashMap<usize, EditorLine>,
+    // Start and end of the last visual selection, `'<` and `'>`.
+    pub visual_marks: Option<((usize, usize), (usize, usize))>,
+    // Values of the local options while another buffer is shown. They are
+    // taken from the global values when the buffer is first shown.
+    pub options: Option<LocalOptions>,
+    // Position of the cursor and of the view when the buffer was left.
+    pub last_cursor: Cursor,
+    pub last_offset: Offset,
+}
+
+impl Buffer {
+    // A file that does not exist yet is edited as an empty one.
+    pub fn open(number: usize, file_path: &str) -> io::Result<Self> {
+        let text = read_file(file_path)?;
+        let history =
+            History::load(&history::undo_file_path(file_path), &text).unwrap_or_else(History::new);
+
+        Ok(Self {
+            file_name: file_path.to_string(),
+            text,
+            syntax_hl: highlight::get_syntax_highlighting(file_path),
+            history,
+            ..Self::empty(number)
+        })
+    }
+
+    // Buffer without a file, edited until one is opened.
+    pub fn empty(number: usize) -> Self {
+        Self {
+            number,
+            file_name: String::new(),
+            text: Rope::from_str(""),
+            dirty: false,
+            syntax_hl: None,
+            history: History::new(),
+            render_cache: HashMap::new(),
+            visual_marks: None,
+            options: None,
+            last_cursor: Cursor {
+                fx: 0,
+                fy: 0,
+                rx: 0,
+            },
+            last_offset: Offset { x: 0, y: 0 },
+        }
+    }
+
+    // Name shown in the status line and by `:ls`.
+    pub fn name(&self) -> &str {
+        match self.file_name.as_str() {
+            "" => "[No Name]",
+            name => name,
+        }
+    }
+}
+
+// Hash of the text as it is written to the file.
+pub fn text_hash(text: &Rope) -> u64 {
+    history::content_hash(
+        text.chunks()
+            .map(str::as_bytes)
+            .chain(std::iter::once(&b"\n"[..])),
+    )
+}
+
+fn read_file(file_path: &str) -> io::Result<Rope> {
+    let mut content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    // Files have a dummy new line at the end that should not be showed.
+    if content.ends_with('\n') {
+        content.pop();
+    }
+    Ok(Rope::from_str(&content))
+}
@@ -0,0 +1,185 @@
+use super::ex;
+use super::modes::command_mode;
+use super::Editor;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Configuration read at startup, chosen with `-u`.
+pub enum Virurc {
+    // The user's virurc, and with `exrc` a trusted `.virurc` in the
+    // current directory.
+    Default,
+    File(String),
+    // `-u NONE`: start from the defaults.
+    None,
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    match env::var_os(var).filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => Some(home_dir()?.join(fallback)),
+    }
+}
+
+// `$XDG_CONFIG_HOME/viru/virurc`, or `~/.virurc` when only that exists.
+fn user_virurc() -> Option<PathBuf> {
+    let path = xdg_dir("XDG_CONFIG_HOME", ".config")?.join("viru/virurc");
+    if path.exists() {
+        return Some(path);
+    }
+    let path = home_dir()?.join(".virurc");
+    path.exists().then_some(path)
+}
+
+// Nested `:source` commands allowed, so that a file sourcing itself fails
+// instead of overflowing the stack.
+const MAX_SOURCE_DEPTH: usize = 20;
+
+// List of the project `.virurc` files allowed to run, one `hash path` per
+// line, with the SHA-256 of their content. Editing a file makes it
+// untrusted again.
+fn trust_file() -> Option<PathBuf> {
+    Some(xdg_dir("XDG_DATA_HOME", ".local/share")?.join("viru/trust"))
+}
+
+fn trust_entry(path: &Path, content: &[u8]) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let hash: String = Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(format!("{} {}", hash, path.display()))
+}
+
+fn is_trusted(path: &Path, content: &[u8]) -> bool {
+    let (Some(entry), Some(trust_file)) = (trust_entry(path, content), trust_file()) else {
+        return false;
+    };
+    fs::read_to_string(trust_file)
+        .map(|trusted| trusted.lines().any(|line| line == entry))
+        .unwrap_or(false)
+}
+
+// Allows the file to run as a project `.virurc` with its current content.
+pub fn trust(path: &Path) -> Result<(), String> {
+    let entry = fs::read(path)
+        .ok()
+        .and_then(|content| trust_entry(path, &content))
+        .ok_or_else(|| format!("Cannot read {}", path.display()))?;
+    let trust_file = trust_file().ok_or("Cannot find the trust file")?;
+    let (_, name) = entry.split_once(' ').unwrap();
+
+    let mut lines: Vec<String> = fs::read_to_string(&trust_file)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.split_once(' ').map(|(_, path)| path) != Some(name))
+        .map(str::to_string)
+        .collect();
+    lines.push(entry);
+
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = trust_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&trust_file, lines.join("\n") + "\n")
+    };
+    write().map_err(|_| format!("Cannot write {}", trust_file.display()))
+}
+
+// Executes the lines of the file as ex commands. Empty lines and the ones
+// starting with `"` are skipped. Returns the errors with their line, in the
+// file sourced by a nested `:source` for its errors.
+//
+// While a project `.virurc` runs, every file sourced must be trusted. The
+// content checked is the one executed, read only once.
+pub fn source(editor: &mut Editor, path: &Path) -> Result<Vec<String>, String> {
+    let cannot_open = || format!("Cannot open file {}", path.display());
+    let content = fs::read(path).map_err(|_| cannot_open())?;
+    if editor.sourcing_project && !is_trusted(path, &content) {
+        return Err(format!(
+            "Untrusted {} not loaded, use :trust to allow it",
+            path.display()
+        ));
+    }
+    let content = String::from_utf8(content).map_err(|_| cannot_open())?;
+    if editor.source_depth >= MAX_SOURCE_DEPTH {
+        return Err("Command too recursive".to_string());
+    }
+    let mut errors = vec![];
+
+    editor.source_depth += 1;
+    for (i, line) in content.lines().enumerate() {
+        let command = line.trim_start_matches([' ', '\t', ':']);
+        if command.is_empty() || command.starts_with('"') {
+            continue;
+        }
+
+        let nested = match ex::parse(editor, command) {
+            Ok(command) if command.name == "source" && !command.arg.is_empty() => Some(command.arg),
+            _ => None,
+        };
+        if let Some(nested) = nested {
+            match source(editor, Path::new(&nested)) {
+                Ok(nested_errors) => errors.extend(nested_errors),
+                Err(message) => {
+                    errors.push(format!("{} line {}: {}", path.display(), i + 1, message))
+                }
+            }
+            continue;
+        }
+
+        editor.failed = false;
+        // A virurc cannot quit the editor.
+        let quit = command_mode::execute_command(editor, command).is_err();
+        if quit || editor.failed {
+            let message = match quit {
+                true => "Cannot quit while sourcing",
+                false => editor.cmd_message.as_str(),
+            };
+            errors.push(format!("{} line {}: {}", path.display(), i + 1, message));
+        }
+    }
+    editor.source_depth -= 1;
+
+    editor.cmd_message.clear();
+    Ok(errors)
+}
+
+fn run(editor: &mut Editor, path: &Path, errors: &mut Vec<String>) {
+    match source(editor, path) {
+        Ok(file_errors) => errors.extend(file_errors),
+        Err(message) => errors.push(message),
+    }
+}
+
+// Reads the configuration at startup. Returns the errors to show.
+pub fn load(editor: &mut Editor, virurc: Virurc) -> Vec<String> {
+    let mut errors = vec![];
+
+    match virurc {
+        Virurc::None => {}
+        Virurc::File(path) => run(editor, Path::new(&path), &mut errors),
+        Virurc::Default => {
+            if let Some(path) = user_virurc() {
+                run(editor, &path, &mut errors);
+            }
+
+            let local = Path::new(".virurc");
+            if editor.options.exrc() && local.exists() {
+                editor.sourcing_project = true;
+                run(editor, local, &mut errors);
+                editor.sourcing_project = false;
+            }
+        }
+    }
+
+    errors
+}
@@ -0,0 +1,240 @@
+use super::searching;
+use super::Editor;
+
+// Names of the ex commands with the shortest abbreviation accepted for
+// each of them.
+const COMMANDS: [(&str, usize); 64] = [
+    ("bNext", 2),
+    ("bdelete", 2),
+    ("bnext", 2),
+    ("bprevious", 2),
+    ("buffer", 1),
+    ("buffers", 7),
+    ("close", 3),
+    ("cmap", 2),
+    ("cnoremap", 3),
+    ("cunmap", 2),
+    ("delete", 1),
+    ("edit", 1),
+    ("earlier", 2),
+    ("exit", 3),
+    ("files", 5),
+    ("imap", 2),
+    ("inoremap", 3),
+    ("iunmap", 2),
+    ("join", 1),
+    ("later", 3),
+    ("ls", 2),
+    ("map", 3),
+    ("nmap", 2),
+    ("nnoremap", 2),
+    ("nohlsearch", 3),
+    ("noremap", 2),
+    ("nunmap", 3),
+    ("omap", 2),
+    ("only", 2),
+    ("onoremap", 3),
+    ("ounmap", 2),
+    ("quit", 1),
+    ("resize", 3),
+    ("saveas", 3),
+    ("set", 2),
+    ("source", 2),
+    ("split", 2),
+    ("setglobal", 4),
+    ("setlocal", 4),
+    ("substitute", 1),
+    ("tabNext", 4),
+    ("tabclose", 4),
+    ("tabedit", 4),
+    ("tabnew", 6),
+    ("tabnext", 4),
+    ("tabonly", 4),
+    ("tabprevious", 4),
+    ("trust", 5),
+    ("unmap", 3),
+    ("vertical", 4),
+    ("vmap", 2),
+    ("vnoremap", 2),
+    ("vsplit", 2),
+    ("vunmap", 2),
+    ("write", 1),
+    ("wq", 2),
+    ("xmap", 2),
+    ("xnoremap", 2),
+    ("xunmap", 2),
+    ("xit", 1),
+    ("yank", 1),
+    ("&", 1),
+    ("<", 1),
+    (">", 1),
+];
+
+// Command line like `:'<,'>w! file`. A command without a name only moves
+// to the last line of its range.
+pub struct ExCommand {
+    // Lines given before the command, both included.
+    pub range: Option<(usize, usize)>,
+    pub name: &'static str,
+    pub bang: bool,
+    pub arg: String,
+}
+
+pub fn parse(editor: &Editor, input: &str) -> Result<ExCommand, String> {
+    let mut s = input.trim_start_matches([' ', ':']);
+
+    let range = parse_range(editor, &mut s)?;
+    s = s.trim_start();
+
+    let name_len = match s.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len()),
+        Some('<' | '>' | '&') => 1,
+        _ => 0,
+    };
+    let name = match &s[..name_len] {
+        "" => "",
+        abbreviation => COMMANDS
+            .iter()
+            .find(|&&(name, min_len)| {
+                abbreviation.len() >= min_len && name.starts_with(abbreviation)
+            })
+            .map(|&(name, _)| name)
+            .ok_or_else(|| format!("Not an editor command: {}", input.trim()))?,
+    };
+    s = &s[name_len..];
+
+    let bang = s.starts_with('!');
+    if bang {
+        s = &s[1..];
+    }
+
+    Ok(ExCommand {
+        range,
+        name,
+        bang,
+        arg: s.trim().to_string(),
+    })
+}
+
+// Parses a range like `%`, `.,$`, `'<,'>` or `/foo/;+2`. With `;` the
+// second address is relative to the first one instead of the cursor.
+fn parse_range(editor: &Editor, s: &mut &str) -> Result<Option<(usize, usize)>, String> {
+    let last_line = editor.buffer.text.num_lines() - 1;
+
+    if let Some(rest) = s.strip_prefix('%') {
+        *s = rest;
+        return Ok(Some((0, last_line)));
+    }
+
+    let mut current = editor.cursor.fy;
+    let mut range = parse_address(editor, s, current)?.map(|y| (y, y));
+
+    while let Some(separator) = s.chars().next().filter(|&c| c == ',' || c == ';') {
+        *s = &s[1..];
+        let first = range.map_or(current, |(_, last)| last);
+        if separator == ';' {
+            current = first;
+        }
+        let last = parse_address(editor, s, current)?.unwrap_or(current);
+        range = Some((first, last));
+    }
+
+    match range {
+        Some((first, last)) if first.max(last) > last_line => Err("Invalid range".to_string()),
+        Some((first, last)) => Ok(Some((first.min(last), first.max(last)))),
+        None => Ok(None),
+    }
+}
+
+// Parses a line address followed by any number of `+N` and `-N` offsets.
+fn parse_address(editor: &Editor, s: &mut &str, current: usize) -> Result<Option<usize>, String> {
+    let base = match s.chars().next() {
+        Some('.') => {
+            *s = &s[1..];
+            current
+        }
+        Some('$') => {
+            *s = &s[1..];
+            editor.buffer.text.num_lines() - 1
+        }
+        Some(c) if c.is_ascii_digit() => parse_number(s).saturating_sub(1),
+        Some('\'') => {
+            let mark = s[1..].chars().next();
+            *s = &s[1 + mark.map_or(0, char::len_utf8)..];
+            let (start, end) = editor.buffer.visual_marks.ok_or("Mark not set")?;
+            match mark {
+                Some('<') => start.0,
+                Some('>') => end.0,
+                _ => return Err("Mark not set".to_string()),
+            }
+        }
+        Some(delimiter @ ('/' | '?')) => {
+            *s = &s[1..];
+            let pattern = parse_pattern(s, delimiter);
+            let pattern = if pattern.is_empty() {
+                editor
+                    .last_pattern
+                    .clone()
+                    .ok_or("No previous regular expression")?
+            } else {
+                pattern
+            };
+            searching::find_line(editor, current, &pattern, delimiter == '/')?
+                .ok_or_else(|| format!("Pattern not found: {}", pattern))?
+        }
+        Some('+' | '-') => current,
+        _ => return Ok(None),
+    };
+
+    let mut y = base as i64;
+    while let Some(sign) = s.chars().next().filter(|&c| c == '+' || c == '-') {
+        *s = &s[1..];
+        let offset = if s.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_number(s).min(i64::MAX as usize) as i64
+        } else {
+            1
+        };
+        y = if sign == '+' {
+            y.saturating_add(offset)
+        } else {
+            y.saturating_sub(offset)
+        };
+    }
+
+    if y < 0 {
+        return Err("Invalid range".to_string());
+    }
+    Ok(Some(y as usize))
+}
+
+fn parse_number(s: &mut &str) -> usize {
+    let len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = s[..len].parse().unwrap_or(usize::MAX);
+    *s = &s[len..];
+    number
+}
+
+// Reads a pattern up to the closing delimiter, which can be escaped with a
+// backslash.
+pub fn parse_pattern(s: &mut &str, delimiter: char) -> String {
+    let mut pattern = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            *s = &s[i + 1..];
+            return pattern;
+        }
+        if c == '\\' && s[i + 1..].starts_with(delimiter) {
+            chars.next();
+            pattern.push(delimiter);
+        } else {
+            pattern.push(c);
+        }
+    }
+
+    *s = "";
+    pattern
+}
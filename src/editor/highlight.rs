@@ -7,6 +7,7 @@ pub struct SyntaxHighlight {
     pub single_line_comment: String,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug)]
 pub enum HLGroup {
     NORMAL,
@@ -15,6 +16,7 @@ pub enum HLGroup {
     KEYWORD,
     TYPE,
     COMMENT,
+    SEARCH,
 }
 
 pub fn hl_group_to_term_color(hl_group: &HLGroup) -> u8 {
@@ -25,6 +27,14 @@ pub fn hl_group_to_term_color(hl_group: &HLGroup) -> u8 {
         HLGroup::KEYWORD => 36,
         HLGroup::TYPE => 33,
         HLGroup::COMMENT => 34,
+        HLGroup::SEARCH => 30,
+    }
+}
+
+pub fn hl_group_to_term_bg_color(hl_group: &HLGroup) -> u8 {
+    match hl_group {
+        HLGroup::SEARCH => 43,
+        _ => 49,
     }
 }
 
@@ -90,16 +100,14 @@ pub fn get_syntax_highlighting(file_path: &str) -> Option<SyntaxHighlight> {
 }
 
 fn is_number(c: u8) -> bool {
-    let c = c as char;
-    '0' <= c && c <= '9'
+    (c as char).is_ascii_digit()
 }
 
 fn is_alpha(c: u8) -> bool {
-    let c = c as char;
-    ('a' <= c && c <= 'z') || ('A' <= c && c <= 'Z') || c == '_'
+    (c as char).is_ascii_alphabetic() || c == b'_'
 }
 
-fn is_alphanumeric(c: u8) -> bool {
+pub fn is_alphanumeric(c: u8) -> bool {
     is_number(c) || is_alpha(c)
 }
 
@@ -125,7 +133,7 @@ impl<'a> Lexer<'a> {
     }
 
     fn get_substring(&self, a: usize, b: usize) -> String {
-        String::from_utf8(self.data[a..b].iter().cloned().collect::<Vec<_>>()).unwrap()
+        String::from_utf8_lossy(&self.data[a..b]).into_owned()
     }
 
     fn get_current_token(&self) -> String {
@@ -133,8 +141,7 @@ impl<'a> Lexer<'a> {
     }
 
     fn is_start_of_comment(&self) -> bool {
-        self.get_substring(self.start, self.data.len())
-            .starts_with(&self.syntax_hl.single_line_comment)
+        self.data[self.start..].starts_with(self.syntax_hl.single_line_comment.as_bytes())
     }
 
     fn set_hl_group(&mut self, hl_group: HLGroup) {
@@ -211,8 +218,6 @@ impl<'a> Lexer<'a> {
             self.scan_comment();
         } else if [b'"', b'\''].contains(&c) {
             self.scan_string(c)
-        } else {
-            ()
         }
     }
 
@@ -227,11 +232,11 @@ impl<'a> Lexer<'a> {
 }
 
 pub fn get_line_highlighting(
-    data: &Vec<u8>,
+    data: &[u8],
     maybe_syntax_hl: &Option<SyntaxHighlight>,
 ) -> Vec<HLGroup> {
     if let Some(syntax_hl) = maybe_syntax_hl {
-        Lexer::new(data.clone(), syntax_hl).tokenize()
+        Lexer::new(data.to_vec(), syntax_hl).tokenize()
     } else {
         vec![HLGroup::NORMAL; data.len()]
     }
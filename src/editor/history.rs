@@ -0,0 +1,619 @@
+use super::buffer;
+use super::rope::Rope;
+use super::Cursor;
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Single reversible modification of the text. Positions are (line, column)
+// pairs and `text` may span several lines when it contains '\n'.
+#[derive(Clone)]
+pub enum Edit {
+    Insert { y: usize, x: usize, text: String },
+    Delete { y: usize, x: usize, text: String },
+}
+
+impl Edit {
+    pub fn inverse(&self) -> Edit {
+        match self {
+            Edit::Insert { y, x, text } => Edit::Delete {
+                y: *y,
+                x: *x,
+                text: text.clone(),
+            },
+            Edit::Delete { y, x, text } => Edit::Insert {
+                y: *y,
+                x: *x,
+                text: text.clone(),
+            },
+        }
+    }
+}
+
+// Position just past `text` when it is placed at (y, x).
+pub fn end_position(y: usize, x: usize, text: &str) -> (usize, usize) {
+    match text.rfind('\n') {
+        Some(i) => (y + text.matches('\n').count(), text.len() - i - 1),
+        None => (y, x + text.len()),
+    }
+}
+
+// Group of edits that is undone and redone as one step.
+#[derive(Clone)]
+pub struct Change {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Cursor,
+    pub cursor_after: Cursor,
+}
+
+// Node of the undo tree. Applying `change` to the text of the parent state
+// gives the text of this state.
+struct UndoState {
+    parent: usize,
+    change: Change,
+    time: u64,
+    // Child that is entered by redo, i.e. the branch that was visited last.
+    redo_child: Option<usize>,
+}
+
+// Undo tree in the style of Vim. States are numbered in the order they were
+// created and state 0 is the text as it was before any recorded change.
+pub struct History {
+    states: Vec<UndoState>,
+    current: usize,
+    pending: Option<Change>,
+}
+
+// Sequence of steps leading from the current state to another one.
+pub struct Path {
+    pub num_undos: usize,
+    pub redos: Vec<usize>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl History {
+    pub fn new() -> Self {
+        let origin = Cursor {
+            fx: 0,
+            fy: 0,
+            rx: 0,
+        };
+
+        Self {
+            states: vec![UndoState {
+                parent: 0,
+                change: Change {
+                    edits: vec![],
+                    cursor_before: origin.clone(),
+                    cursor_after: origin,
+                },
+                time: now(),
+                redo_child: None,
+            }],
+            current: 0,
+            pending: None,
+        }
+    }
+
+    // Starts grouping edits. Does nothing if a group is already open, so
+    // e.g. an insert-mode session started by `o` stays a single step.
+    pub fn begin(&mut self, cursor: &Cursor) {
+        if self.pending.is_none() {
+            self.pending = Some(Change {
+                edits: vec![],
+                cursor_before: cursor.clone(),
+                cursor_after: cursor.clone(),
+            });
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit, cursor: &Cursor) {
+        self.begin(cursor);
+        if let Some(change) = &mut self.pending {
+            change.edits.push(edit);
+        }
+    }
+
+    // Closes the current group and adds it as a new branch of the tree, so
+    // the states that were undone before stay reachable.
+    pub fn end(&mut self, cursor: &Cursor) {
+        if let Some(mut change) = self.pending.take() {
+            if !change.edits.is_empty() {
+                change.cursor_after = cursor.clone();
+
+                let seq = self.states.len();
+                self.states.push(UndoState {
+                    parent: self.current,
+                    change,
+                    time: now(),
+                    redo_child: None,
+                });
+                self.states[self.current].redo_child = Some(seq);
+                self.current = seq;
+            }
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    // Moves to the parent state. The returned change has to be reverted.
+    pub fn undo(&mut self) -> Option<Change> {
+        if self.current == 0 {
+            return None;
+        }
+
+        let state = &self.states[self.current];
+        let parent = state.parent;
+        let change = state.change.clone();
+
+        self.states[parent].redo_child = Some(self.current);
+        self.current = parent;
+        Some(change)
+    }
+
+    // Moves to the most recently visited child. The returned change has to
+    // be applied.
+    pub fn redo(&mut self) -> Option<Change> {
+        let child = self.states[self.current].redo_child?;
+        Some(self.redo_to(child))
+    }
+
+    pub fn redo_to(&mut self, child: usize) -> Change {
+        assert_eq!(self.states[child].parent, self.current);
+
+        self.states[self.current].redo_child = Some(child);
+        self.current = child;
+        self.states[child].change.clone()
+    }
+
+    fn ancestors(&self, mut seq: usize) -> Vec<usize> {
+        let mut res = vec![seq];
+        while seq != 0 {
+            seq = self.states[seq].parent;
+            res.push(seq);
+        }
+        res
+    }
+
+    pub fn path_to(&self, target: usize) -> Path {
+        let from = self.ancestors(self.current);
+        let to = self.ancestors(target);
+
+        let common = *to.iter().find(|seq| from.contains(seq)).unwrap();
+        let num_undos = from.iter().position(|&seq| seq == common).unwrap();
+        let num_redos = to.iter().position(|&seq| seq == common).unwrap();
+
+        Path {
+            num_undos,
+            redos: to[..num_redos].iter().rev().cloned().collect(),
+        }
+    }
+
+    // State that was current `seconds` before (or after, for negative
+    // values) the current one.
+    pub fn state_by_time(&self, seconds: i64) -> usize {
+        let time = (self.states[self.current].time as i64).saturating_sub(seconds);
+
+        let found = (0..self.states.len())
+            .rev()
+            .find(|&seq| self.states[seq].time as i64 <= time)
+            .unwrap_or(0);
+
+        if seconds < 0 {
+            found.max(self.current)
+        } else {
+            found.min(self.current)
+        }
+    }
+
+    // The file format is line based: a header, then every state followed by
+    // its edits. Text of the edits is escaped so that it fits on one line.
+    pub fn save(&self, path: &str, content_hash: u64) -> io::Result<()> {
+        let mut out = format!(
+            "viru-undo 1\nhash {:016x}\ncurrent {}\n",
+            content_hash, self.current
+        );
+
+        for state in &self.states {
+            let change = &state.change;
+            out.push_str(&format!(
+                "state {} {} {} {} {} {} {} {}\n",
+                state.parent,
+                state.time,
+                state.redo_child.map(|c| c as i64).unwrap_or(-1),
+                change.cursor_before.fx,
+                change.cursor_before.fy,
+                change.cursor_after.fx,
+                change.cursor_after.fy,
+                change.edits.len(),
+            ));
+
+            for edit in &change.edits {
+                let (kind, y, x, text) = match edit {
+                    Edit::Insert { y, x, text } => ("i", y, x, text),
+                    Edit::Delete { y, x, text } => ("d", y, x, text),
+                };
+                out.push_str(&format!("{} {} {} {}\n", kind, y, x, escape(text)));
+            }
+        }
+
+        fs::write(path, out)
+    }
+
+    // Returns None when the file is missing, malformed, inconsistent or
+    // belongs to a different content of the edited file, `text`.
+    pub fn load(path: &str, text: &Rope) -> Option<Self> {
+        let data = fs::read_to_string(path).ok()?;
+        let mut lines = data.lines();
+
+        if lines.next()? != "viru-undo 1" {
+            return None;
+        }
+        let hash = u64::from_str_radix(lines.next()?.strip_prefix("hash ")?, 16).ok()?;
+        if hash != buffer::text_hash(text) {
+            return None;
+        }
+        let current: usize = lines.next()?.strip_prefix("current ")?.parse().ok()?;
+
+        let mut states = vec![];
+        while let Some(line) = lines.next() {
+            let fields: Vec<i64> = line
+                .strip_prefix("state ")?
+                .split(' ')
+                .map(|f| f.parse().ok())
+                .collect::<Option<_>>()?;
+            if fields.len() != 8 || fields.iter().any(|&f| f < -1) {
+                return None;
+            }
+
+            let cursor = |fx: i64, fy: i64| Cursor {
+                fx: fx as usize,
+                fy: fy as usize,
+                rx: 0,
+            };
+            let mut change = Change {
+                edits: vec![],
+                cursor_before: cursor(fields[3], fields[4]),
+                cursor_after: cursor(fields[5], fields[6]),
+            };
+
+            for _ in 0..fields[7] {
+                let mut parts = lines.next()?.splitn(4, ' ');
+                let kind = parts.next()?;
+                let y = parts.next()?.parse().ok()?;
+                let x = parts.next()?.parse().ok()?;
+                let text = unescape(parts.next()?);
+
+                change.edits.push(match kind {
+                    "i" => Edit::Insert { y, x, text },
+                    "d" => Edit::Delete { y, x, text },
+                    _ => return None,
+                });
+            }
+
+            states.push(UndoState {
+                parent: fields[0] as usize,
+                change,
+                time: fields[1] as u64,
+                redo_child: if fields[2] < 0 {
+                    None
+                } else {
+                    Some(fields[2] as usize)
+                },
+            });
+        }
+
+        let valid = current < states.len()
+            && states[0].change.edits.is_empty()
+            && states.iter().enumerate().all(|(seq, state)| {
+                (seq == 0 || state.parent < seq)
+                    && state
+                        .redo_child
+                        .is_none_or(|c| c > seq && c < states.len() && states[c].parent == seq)
+            });
+        if !valid {
+            return None;
+        }
+
+        let history = Self {
+            states,
+            current,
+            pending: None,
+        };
+        history.fits(text).then_some(history)
+    }
+
+    // Whether every change applies to the text of its parent state, the
+    // text of the current state being `text`. The states are visited
+    // without recursion, as the tree can be as deep as it is long.
+    fn fits(&self, text: &Rope) -> bool {
+        let mut text = Rope::from_str(&text.chunks().collect::<String>());
+
+        let mut seq = self.current;
+        while seq != 0 {
+            if !self.revert(&mut text, seq) {
+                return false;
+            }
+            seq = self.states[seq].parent;
+        }
+
+        let mut children = vec![vec![]; self.states.len()];
+        for seq in 1..self.states.len() {
+            children[self.states[seq].parent].push(seq);
+        }
+
+        // A state is entered by applying its change and left by reverting
+        // it, after its children have been visited.
+        let mut stack: Vec<(usize, bool)> = children[0].iter().map(|&c| (c, true)).collect();
+        while let Some((seq, enter)) = stack.pop() {
+            let change = &self.states[seq].change;
+            let fits = match enter {
+                true => {
+                    cursor_fits(&text, &change.cursor_before)
+                        && change
+                            .edits
+                            .iter()
+                            .all(|edit| apply_checked(&mut text, edit))
+                        && cursor_fits(&text, &change.cursor_after)
+                }
+                false => self.revert(&mut text, seq),
+            };
+            if !fits {
+                return false;
+            }
+            if enter {
+                stack.push((seq, false));
+                stack.extend(children[seq].iter().map(|&c| (c, true)));
+            }
+        }
+        true
+    }
+
+    fn revert(&self, text: &mut Rope, seq: usize) -> bool {
+        let change = &self.states[seq].change;
+        change
+            .edits
+            .iter()
+            .rev()
+            .all(|edit| apply_checked(text, &edit.inverse()))
+            && cursor_fits(text, &change.cursor_before)
+    }
+}
+
+// Whether the cursor is on a character of the text, or just past the end
+// of its line.
+fn cursor_fits(text: &Rope, cursor: &Cursor) -> bool {
+    cursor.fy < text.num_lines() && text.line(cursor.fy).is_char_boundary(cursor.fx)
+}
+
+// Applies the edit when its positions are within the text and, for
+// a deletion, the text at them is the deleted one.
+fn apply_checked(text: &mut Rope, edit: &Edit) -> bool {
+    let (Edit::Insert { y, x, text: s } | Edit::Delete { y, x, text: s }) = edit;
+    let fits =
+        |text: &Rope, y: usize, x: usize| y < text.num_lines() && text.line(y).is_char_boundary(x);
+    if !fits(text, *y, *x) {
+        return false;
+    }
+    let at = text.line_to_byte(*y) + x;
+
+    match edit {
+        Edit::Insert { .. } => text.insert(at, s),
+        Edit::Delete { .. } => {
+            let (end_y, end_x) = end_position(*y, *x, s);
+            if !fits(text, end_y, end_x)
+                || text.line_to_byte(end_y) + end_x != at + s.len()
+                || text.slice(at, at + s.len()) != *s
+            {
+                return false;
+            }
+            text.remove(at, at + s.len());
+        }
+    }
+    true
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut res = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => res.push('\n'),
+                Some(c) => res.push(c),
+                None => {}
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+// FNV-1a, used to check that an undo file matches the edited file.
+pub fn content_hash<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in chunks.flatten() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Name of the file keeping the history of `file_path`, placed next to it.
+pub fn undo_file_path(file_path: &str) -> String {
+    match file_path.rfind('/') {
+        Some(i) => format!("{}/.{}.viru-undo", &file_path[..i], &file_path[i + 1..]),
+        None => format!(".{}.viru-undo", file_path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(fy: usize, fx: usize) -> Cursor {
+        Cursor { fx, fy, rx: 0 }
+    }
+
+    fn change(history: &mut History, text: &mut Rope, edit: Edit, after: (usize, usize)) {
+        history.begin(&cursor(0, 0));
+        assert!(apply_checked(text, &edit));
+        history.record(edit, &cursor(0, 0));
+        history.end(&cursor(after.0, after.1));
+    }
+
+    // History with a branch undone, and the text of its current state.
+    fn sample() -> (History, Rope) {
+        let mut history = History::new();
+        let mut text = Rope::from_str("");
+        let (y, x) = (0, 0);
+        let text_1 = "ab\nżc".to_string();
+        change(
+            &mut history,
+            &mut text,
+            Edit::Insert { y, x, text: text_1 },
+            (1, 2),
+        );
+        let text_2 = "b".to_string();
+        change(
+            &mut history,
+            &mut text,
+            Edit::Delete {
+                y,
+                x: 1,
+                text: text_2,
+            },
+            (0, 1),
+        );
+
+        let undone = history.undo().unwrap();
+        assert!(undone
+            .edits
+            .iter()
+            .all(|edit| apply_checked(&mut text, &edit.inverse())));
+        let text_3 = "x".to_string();
+        change(
+            &mut history,
+            &mut text,
+            Edit::Insert {
+                y: 1,
+                x: 3,
+                text: text_3,
+            },
+            (1, 3),
+        );
+        (history, text)
+    }
+
+    fn temp_path(name: &str) -> String {
+        let name = format!("viru-undo-{}-{}", std::process::id(), name);
+        std::env::temp_dir().join(name).display().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (history, text) = sample();
+        let path = temp_path("round-trip");
+        history.save(&path, buffer::text_hash(&text)).unwrap();
+
+        let mut loaded = History::load(&path, &text).unwrap();
+        assert_eq!((loaded.current(), loaded.num_states()), (3, 4));
+        let copy = temp_path("round-trip-copy");
+        loaded.save(&copy, buffer::text_hash(&text)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            fs::read_to_string(&copy).unwrap()
+        );
+
+        // The undone branch is still there.
+        assert!(loaded.undo().is_some());
+        let change = loaded.redo_to(2);
+        assert!(matches!(
+            &change.edits[..],
+            [Edit::Delete { y: 0, x: 1, .. }]
+        ));
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(copy).unwrap();
+    }
+
+    // Saves the sample history, changes a field of a line of the file and
+    // checks that it is no longer loaded.
+    fn assert_rejected(name: &str, line: usize, field: usize, value: &str) {
+        let (history, text) = sample();
+        let path = temp_path(name);
+        history.save(&path, buffer::text_hash(&text)).unwrap();
+        assert!(History::load(&path, &text).is_some());
+
+        let data = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = data.lines().map(str::to_string).collect();
+        let mut fields: Vec<&str> = lines[line].split(' ').collect();
+        fields[field] = value;
+        lines[line] = fields.join(" ");
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        assert!(History::load(&path, &text).is_none(), "{}", name);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert_rejected("version", 0, 1, "2");
+        assert_rejected("hash", 1, 1, "0");
+        assert_rejected("current", 2, 1, "4");
+        assert_rejected("number", 4, 1, "x");
+        assert_rejected("kind", 5, 0, "r");
+    }
+
+    #[test]
+    fn inconsistent_trees_are_rejected() {
+        // State 3 is a child of 1, not of 0.
+        assert_rejected("redo-child", 3, 3, "3");
+        assert_rejected("later-parent", 6, 1, "3");
+        assert_rejected("edits-in-root", 3, 8, "1");
+    }
+
+    #[test]
+    fn edits_outside_the_text_are_rejected() {
+        assert_rejected("line", 9, 1, "2");
+        assert_rejected("column", 9, 2, "5");
+        assert_rejected("inside-char", 9, 2, "1");
+        assert_rejected("deleted-text", 7, 3, "a");
+    }
+
+    #[test]
+    fn cursors_outside_the_text_are_rejected() {
+        assert_rejected("cursor-line", 8, 7, "2");
+        assert_rejected("cursor-column", 8, 6, "9");
+        assert_rejected("cursor-inside-char", 8, 6, "1");
+        assert_rejected("cursor-before", 6, 4, "7");
+    }
+
+    #[test]
+    fn other_texts_and_missing_files_are_rejected() {
+        let (history, text) = sample();
+        let path = temp_path("other-text");
+        history.save(&path, buffer::text_hash(&text)).unwrap();
+        assert!(History::load(&path, &Rope::from_str("ab\nżc")).is_none());
+        fs::remove_file(&path).unwrap();
+
+        assert!(History::load(&path, &text).is_none());
+    }
+}
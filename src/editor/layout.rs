@@ -0,0 +1,392 @@
+use super::{Cursor, Offset};
+
+// Part of the screen taken by a window. The height counts its status line,
+// the width does not count the separator drawn on its right.
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// State of a window other than the current one, whose state is kept in the
+// editor.
+pub struct View {
+    pub id: usize,
+    // Number of the buffer shown.
+    pub buffer: usize,
+    pub cursor: Cursor,
+    pub offset: Offset,
+}
+
+// Windows side by side when `vertical`, stacked otherwise, with their width
+// or height.
+enum Frame {
+    Window(usize),
+    Split {
+        vertical: bool,
+        children: Vec<(Frame, usize)>,
+    },
+}
+
+// Smallest height of a window: a line of text and the status line.
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+
+fn min_size(vertical: bool) -> usize {
+    if vertical {
+        MIN_WIDTH
+    } else {
+        MIN_HEIGHT
+    }
+}
+
+// Columns taken by the separators between `n` windows side by side.
+fn separators(vertical: bool, n: usize) -> usize {
+    if vertical {
+        n - 1
+    } else {
+        0
+    }
+}
+
+impl Frame {
+    fn contains(&self, id: usize) -> bool {
+        match self {
+            Frame::Window(w) => *w == id,
+            Frame::Split { children, .. } => children.iter().any(|(child, _)| child.contains(id)),
+        }
+    }
+
+    // Smallest width when `vertical`, or height, the frame can be given.
+    fn min_extent(&self, vertical: bool) -> usize {
+        match self {
+            Frame::Window(_) => min_size(vertical),
+            Frame::Split {
+                vertical: v,
+                children,
+            } if *v == vertical => {
+                children
+                    .iter()
+                    .map(|(child, _)| child.min_extent(vertical))
+                    .sum::<usize>()
+                    + separators(vertical, children.len())
+            }
+            Frame::Split { children, .. } => children
+                .iter()
+                .map(|(child, _)| child.min_extent(vertical))
+                .max()
+                .unwrap(),
+        }
+    }
+
+    // Gives the frame another width or height. The last windows grow or
+    // shrink first.
+    fn set_extent(&mut self, vertical: bool, extent: usize) {
+        let Frame::Split {
+            vertical: v,
+            children,
+        } = self
+        else {
+            return;
+        };
+        if *v != vertical {
+            for (child, _) in children.iter_mut() {
+                child.set_extent(vertical, extent);
+            }
+            return;
+        }
+
+        let total = extent - separators(vertical, children.len());
+        let mut current: usize = children.iter().map(|(_, size)| size).sum();
+        for (child, size) in children.iter_mut().rev() {
+            if current < total {
+                *size += total - current;
+                current = total;
+            } else if current > total {
+                let shrink = (current - total).min(*size - child.min_extent(vertical));
+                *size -= shrink;
+                current -= shrink;
+            }
+            child.set_extent(vertical, *size);
+        }
+    }
+
+    fn equalize(&mut self, width: usize, height: usize) {
+        let Frame::Split { vertical, children } = self else {
+            return;
+        };
+        let extent = if *vertical { width } else { height };
+        let n = children.len();
+        let total = extent - separators(*vertical, n);
+
+        for (i, (child, size)) in children.iter_mut().enumerate() {
+            *size = total / n + usize::from(i < total % n);
+            match vertical {
+                true => child.equalize(*size, height),
+                false => child.equalize(width, *size),
+            }
+        }
+    }
+
+    fn rects(&self, rect: Rect, rects: &mut Vec<(usize, Rect)>) {
+        match self {
+            Frame::Window(id) => rects.push((*id, rect)),
+            Frame::Split { vertical, children } => {
+                let mut start = if *vertical { rect.x } else { rect.y };
+                for (child, size) in children {
+                    let child_rect = match vertical {
+                        true => Rect {
+                            x: start,
+                            width: *size,
+                            ..rect
+                        },
+                        false => Rect {
+                            y: start,
+                            height: *size,
+                            ..rect
+                        },
+                    };
+                    child.rects(child_rect, rects);
+                    start += size + separators(*vertical, 2);
+                }
+            }
+        }
+    }
+
+    // Puts the window `new` before the window `id`, sharing its space.
+    fn split(&mut self, id: usize, new: usize, vertical: bool, sizes: (usize, usize)) -> bool {
+        match self {
+            Frame::Window(w) if *w == id => {
+                *self = Frame::Split {
+                    vertical,
+                    children: vec![(Frame::Window(new), sizes.0), (Frame::Window(id), sizes.1)],
+                };
+                true
+            }
+            Frame::Window(_) => false,
+            Frame::Split {
+                vertical: v,
+                children,
+            } => {
+                let found = children
+                    .iter()
+                    .position(|(child, _)| matches!(child, Frame::Window(w) if *w == id));
+                match found {
+                    Some(i) if *v == vertical => {
+                        children[i].1 = sizes.1;
+                        children.insert(i, (Frame::Window(new), sizes.0));
+                        true
+                    }
+                    _ => children
+                        .iter_mut()
+                        .any(|(child, _)| child.split(id, new, vertical, sizes)),
+                }
+            }
+        }
+    }
+
+    // Removes the window. Its space goes to the next window, or to the
+    // previous one for the last.
+    fn close(&mut self, id: usize) -> bool {
+        let Frame::Split { vertical, children } = self else {
+            return false;
+        };
+        let vertical = *vertical;
+        let Some(i) = children
+            .iter()
+            .position(|(child, _)| matches!(child, Frame::Window(w) if *w == id))
+        else {
+            return children.iter_mut().any(|(child, _)| child.close(id));
+        };
+
+        let (_, size) = children.remove(i);
+        let j = i.min(children.len() - 1);
+        let (sibling, sibling_size) = &mut children[j];
+        *sibling_size += size + separators(vertical, 2);
+        sibling.set_extent(vertical, *sibling_size);
+
+        if children.len() == 1 {
+            let (only, _) = children.pop().unwrap();
+            *self = only;
+        }
+        true
+    }
+
+    // Sets the width or height of the window, or of the frame holding it
+    // when the window is not beside another one in that direction. The
+    // space is taken from the next windows first.
+    fn resize(&mut self, id: usize, vertical: bool, size: usize) -> bool {
+        let Frame::Split {
+            vertical: v,
+            children,
+        } = self
+        else {
+            return false;
+        };
+        let i = children
+            .iter()
+            .position(|(child, _)| child.contains(id))
+            .unwrap();
+        if children[i].0.resize(id, vertical, size) {
+            return true;
+        }
+        if *v != vertical {
+            return false;
+        }
+
+        let available: usize = children
+            .iter()
+            .enumerate()
+            .filter(|&(j, _)| j != i)
+            .map(|(_, (child, size))| size - child.min_extent(vertical))
+            .sum();
+        let current = children[i].1;
+        let size = size
+            .max(children[i].0.min_extent(vertical))
+            .min(current + available);
+
+        let others = (i + 1..children.len()).chain((0..i).rev());
+        if size > current {
+            let mut needed = size - current;
+            for j in others {
+                let (child, child_size) = &mut children[j];
+                let taken = needed.min(*child_size - child.min_extent(vertical));
+                *child_size -= taken;
+                child.set_extent(vertical, *child_size);
+                needed -= taken;
+            }
+        } else if let Some(j) = others.take(1).next() {
+            let (child, child_size) = &mut children[j];
+            *child_size += current - size;
+            child.set_extent(vertical, *child_size);
+        }
+
+        let (child, child_size) = &mut children[i];
+        *child_size = size;
+        child.set_extent(vertical, size);
+        true
+    }
+}
+
+// Windows on the screen, every one showing a buffer.
+pub struct Layout {
+    root: Frame,
+    // Row where the windows start, below the tabline, and size of the part
+    // of the screen they take.
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+    // Identifier of the current window.
+    pub current: usize,
+    pub views: Vec<View>,
+    next_id: usize,
+}
+
+impl Layout {
+    // A single window taking the whole area.
+    pub fn new(top: usize, width: usize, height: usize) -> Self {
+        Self {
+            root: Frame::Window(0),
+            top,
+            width,
+            height,
+            current: 0,
+            views: vec![],
+            next_id: 1,
+        }
+    }
+
+    // Identifiers and places of the windows, from top left to bottom right.
+    pub fn rects(&self) -> Vec<(usize, Rect)> {
+        let mut rects = vec![];
+        let screen = Rect {
+            x: 0,
+            y: self.top,
+            width: self.width,
+            height: self.height,
+        };
+        self.root.rects(screen, &mut rects);
+        rects
+    }
+
+    pub fn rect(&self, id: usize) -> Rect {
+        self.rects()
+            .into_iter()
+            .find(|&(w, _)| w == id)
+            .map(|(_, rect)| rect)
+            .unwrap()
+    }
+
+    pub fn num_windows(&self) -> usize {
+        self.rects().len()
+    }
+
+    pub fn view(&self, id: usize) -> Option<&View> {
+        self.views.iter().find(|view| view.id == id)
+    }
+
+    // Splits the current window in two, like `:split` or `:vsplit`. The
+    // new window comes above or on the left. Returns its identifier.
+    pub fn split(&mut self, vertical: bool) -> Result<usize, String> {
+        let rect = self.rect(self.current);
+        let extent = match vertical {
+            true => rect.width,
+            false => rect.height,
+        };
+        let available = extent - separators(vertical, 2);
+        if available < 2 * min_size(vertical) {
+            return Err("Not enough room".to_string());
+        }
+
+        let new = self.next_id;
+        self.next_id += 1;
+        let sizes = (available - available / 2, available / 2);
+        self.root.split(self.current, new, vertical, sizes);
+        Ok(new)
+    }
+
+    // Removes a window other than the last one. Its view is dropped.
+    pub fn close(&mut self, id: usize) {
+        self.root.close(id);
+        self.views.retain(|view| view.id != id);
+    }
+
+    // Sets the width or the height of the current window.
+    pub fn resize(&mut self, vertical: bool, size: usize) {
+        self.root.resize(self.current, vertical, size);
+    }
+
+    // Gives all the windows the same size, like CTRL-W =.
+    pub fn equalize(&mut self) {
+        self.root.equalize(self.width, self.height);
+    }
+
+    // Window next to the current one in the direction of `h`, `j`, `k` or
+    // `l`, the one beside the screen position `(row, col)` if several are.
+    pub fn neighbor(&self, direction: char, (row, col): (usize, usize)) -> Option<usize> {
+        let rect = self.rect(self.current);
+        let beside = |other: &Rect| match direction {
+            'h' => other.x + other.width + 1 == rect.x,
+            'l' => rect.x + rect.width + 1 == other.x,
+            'k' => other.y + other.height == rect.y,
+            _ => rect.y + rect.height == other.y,
+        };
+        let faces = |other: &Rect| match direction {
+            'h' | 'l' => (other.y..other.y + other.height).contains(&row),
+            _ => (other.x..other.x + other.width + 1).contains(&col),
+        };
+
+        let candidates: Vec<(usize, Rect)> = self
+            .rects()
+            .into_iter()
+            .filter(|(_, other)| beside(other))
+            .collect();
+        candidates
+            .iter()
+            .find(|(_, other)| faces(other))
+            .or(candidates.first())
+            .map(|&(id, _)| id)
+    }
+}
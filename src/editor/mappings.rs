@@ -0,0 +1,202 @@
+use crate::terminal::input::Key;
+
+// Mode a mapping applies in, `n`, `v`, `o`, `i` or `c` as in `:nmap`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MapMode {
+    Normal,
+    Visual,
+    OperatorPending,
+    Insert,
+    CommandLine,
+}
+
+impl MapMode {
+    fn letter(self) -> char {
+        match self {
+            MapMode::Normal => 'n',
+            MapMode::Visual => 'v',
+            MapMode::OperatorPending => 'o',
+            MapMode::Insert => 'i',
+            MapMode::CommandLine => 'c',
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Mapping {
+    pub mode: MapMode,
+    pub lhs: Vec<Key>,
+    pub rhs: Vec<Key>,
+    // The keys of `rhs` are not mapped again.
+    pub noremap: bool,
+}
+
+// Parses keys written like `<leader>w` or `:w<CR>`.
+pub fn parse_keys(s: &str, leader: &str) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut rest = s;
+
+    while let Some(c) = rest.chars().next() {
+        let name = rest
+            .strip_prefix('<')
+            .and_then(|after| after.split_once('>'))
+            .map(|(name, _)| name);
+        let special = name.and_then(|name| match name.to_lowercase().as_str() {
+            "cr" | "enter" | "return" => Some(vec![Key::Enter]),
+            "esc" => Some(vec![Key::Escape]),
+            "bs" => Some(vec![Key::Backspace]),
+            "tab" => Some(vec![Key::Control('i')]),
+            "space" => Some(vec![Key::Char(' ')]),
+            "lt" => Some(vec![Key::Char('<')]),
+            "bar" => Some(vec![Key::Char('|')]),
+            "bslash" => Some(vec![Key::Char('\\')]),
+            "nop" => Some(vec![]),
+            "leader" => Some(leader.chars().map(Key::from_char).collect()),
+            lower => match lower.strip_prefix("c-").map(|c| c.as_bytes()) {
+                Some(&[c]) if c.is_ascii_lowercase() => Some(vec![Key::Control(c as char)]),
+                _ => None,
+            },
+        });
+
+        match (name, special) {
+            (Some(name), Some(special)) => {
+                keys.extend(special);
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                keys.push(Key::from_char(c));
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    keys
+}
+
+// Name of the keys in mapping listings.
+fn key_names(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|&key| match key {
+            Key::Enter => "<CR>".to_string(),
+            Key::Escape => "<Esc>".to_string(),
+            Key::Backspace => "<BS>".to_string(),
+            Key::Control('i') => "<Tab>".to_string(),
+            Key::Control(c) => format!("<C-{}>", c.to_ascii_uppercase()),
+            Key::Char(' ') => "<Space>".to_string(),
+            key => key.to_char().to_string(),
+        })
+        .collect()
+}
+
+// Modes and kind of a mapping command. `:map` applies in normal, visual
+// and operator-pending mode, `:map!` in insert and command-line mode.
+fn parse_command(name: &str, bang: bool) -> Option<(Vec<MapMode>, &str)> {
+    ["", "n", "v", "x", "o", "i", "c"]
+        .iter()
+        .find_map(|&prefix| {
+            let kind = name.strip_prefix(prefix)?;
+            let modes = match prefix {
+                "" if bang => vec![MapMode::Insert, MapMode::CommandLine],
+                "" => vec![MapMode::Normal, MapMode::Visual, MapMode::OperatorPending],
+                "n" => vec![MapMode::Normal],
+                "v" | "x" => vec![MapMode::Visual],
+                "o" => vec![MapMode::OperatorPending],
+                "i" => vec![MapMode::Insert],
+                _ => vec![MapMode::CommandLine],
+            };
+            ["map", "noremap", "unmap"]
+                .contains(&kind)
+                .then_some((modes, kind))
+        })
+}
+
+pub fn is_command(name: &str) -> bool {
+    parse_command(name, false).is_some()
+}
+
+#[derive(Default)]
+pub struct Mappings {
+    mappings: Vec<Mapping>,
+}
+
+impl Mappings {
+    // Longest mapping whose left-hand side starts the keys, and whether
+    // more keys could match a longer one.
+    pub fn lookup(&self, mode: MapMode, keys: &[Key]) -> (Option<&Mapping>, bool) {
+        let in_mode = || self.mappings.iter().filter(move |m| m.mode == mode);
+        let found = in_mode()
+            .filter(|m| keys.starts_with(&m.lhs))
+            .max_by_key(|m| m.lhs.len());
+        let longer = in_mode().any(|m| m.lhs.len() > keys.len() && m.lhs.starts_with(keys));
+        (found, longer)
+    }
+
+    // Executes a command like `:nnoremap <leader>w :w<CR>` or `:iunmap jk`.
+    // Without a right-hand side the mappings starting with the keys are
+    // listed.
+    pub fn command(
+        &mut self,
+        name: &str,
+        bang: bool,
+        arg: &str,
+        leader: &str,
+    ) -> Result<String, String> {
+        let (modes, kind) = parse_command(name, bang).unwrap();
+        let (lhs, rhs) = match arg.split_once(char::is_whitespace) {
+            Some((lhs, rhs)) => (lhs, rhs.trim_start()),
+            None => (arg, ""),
+        };
+        let lhs = parse_keys(lhs, leader);
+
+        if kind == "unmap" {
+            if lhs.is_empty() {
+                return Err("Argument required".to_string());
+            }
+            let len = self.mappings.len();
+            self.mappings
+                .retain(|m| !(modes.contains(&m.mode) && m.lhs == lhs));
+            if self.mappings.len() == len {
+                return Err("No such mapping".to_string());
+            }
+            return Ok(String::new());
+        }
+
+        if rhs.is_empty() {
+            let listed: Vec<String> = self
+                .mappings
+                .iter()
+                .filter(|m| modes.contains(&m.mode) && m.lhs.starts_with(&lhs))
+                .map(|m| {
+                    let noremap = if m.noremap { "*" } else { " " };
+                    format!(
+                        "{} {} {}{}",
+                        m.mode.letter(),
+                        key_names(&m.lhs),
+                        noremap,
+                        key_names(&m.rhs)
+                    )
+                })
+                .collect();
+            return match listed.len() {
+                0 => Ok("No mapping found".to_string()),
+                _ => Ok(listed.join("  ")),
+            };
+        }
+
+        // `:map <Nop> x` would apply before every key.
+        if lhs.is_empty() {
+            return Err("Invalid argument".to_string());
+        }
+        let rhs = parse_keys(rhs, leader);
+        for mode in modes {
+            self.mappings.retain(|m| !(m.mode == mode && m.lhs == lhs));
+            self.mappings.push(Mapping {
+                mode,
+                lhs: lhs.clone(),
+                rhs: rhs.clone(),
+                noremap: kind == "noremap",
+            });
+        }
+        Ok(String::new())
+    }
+}
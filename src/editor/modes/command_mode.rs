@@ -1,11 +1,20 @@
 use super::super::Mode;
-use crate::editor::Editor;
-use crate::terminal::input::{read_key, Key};
+use super::normal_mode::{self, QuitError};
+use crate::editor::buffer::Buffer;
+use crate::editor::ex::{self, ExCommand};
+use crate::editor::operators::{self, Operator, Range};
+use crate::editor::options::Scope;
+use crate::editor::{config, highlight, mappings, motions, registers, substitute, Editor};
+use crate::terminal::input::Key;
+use std::path::Path;
+
+// Called with the input after every key typed at the prompt.
+pub type Callback<'a> = &'a mut dyn FnMut(&mut Editor, &str);
 
 pub fn enter_command(
     editor: &mut Editor,
     prompt_str: &str,
-    callback: Option<fn(&mut Editor, &str) -> bool>,
+    mut callback: Option<Callback>,
 ) -> Option<String> {
     let mut prompt = String::new();
     editor.mode = Mode::COMMAND;
@@ -14,7 +23,7 @@ pub fn enter_command(
         editor.cmd_message = prompt_str.to_string() + &prompt;
         editor.refresh_screen();
 
-        let key = read_key();
+        let key = editor.read_mapped_key();
 
         match key {
             Key::Enter => {
@@ -26,16 +35,667 @@ pub fn enter_command(
                 return None;
             }
             Key::Char(c) => prompt.push(c),
+            Key::Slash => prompt.push('/'),
+            Key::Colon => prompt.push(':'),
             Key::Backspace => {
-                if !prompt.is_empty() {
-                    prompt.pop();
-                }
+                prompt.pop();
             }
             _ => {}
         }
 
-        if let Some(c) = callback {
+        if let Some(c) = callback.as_mut() {
             c(editor, &prompt);
         }
     }
 }
+
+pub fn execute_command(editor: &mut Editor, input: &str) -> Result<(), QuitError> {
+    let command = match ex::parse(editor, input) {
+        Ok(command) => command,
+        Err(message) => {
+            editor.cmd_message = message;
+            editor.fail();
+            return Ok(());
+        }
+    };
+    let lines = command
+        .range
+        .unwrap_or((editor.cursor.fy, editor.cursor.fy));
+
+    match command.name {
+        "" => {
+            if let Some((_, last)) = command.range {
+                editor.cursor.fy = last;
+                editor.cursor.fx = motions::first_non_blank(editor, last);
+            }
+        }
+        "quit" => return quit(editor, command.bang),
+        "write" => {
+            write(editor, &command);
+        }
+        "wq" => {
+            if write(editor, &command) {
+                return quit(editor, command.bang);
+            }
+        }
+        "xit" | "exit" => {
+            // The file is only written if there are changes.
+            let unchanged = !editor.buffer.dirty && command.arg.is_empty();
+            if unchanged || write(editor, &command) {
+                return quit(editor, command.bang);
+            }
+        }
+        "edit" => edit(editor, &command),
+        "buffer" => match find_buffer(editor, &command.arg) {
+            Ok(number) => edit_buffer(editor, number),
+            Err(message) => {
+                editor.cmd_message = message;
+                editor.fail();
+            }
+        },
+        "bnext" => cycle_buffers(editor, &command.arg, true),
+        "bNext" | "bprevious" => cycle_buffers(editor, &command.arg, false),
+        "bdelete" => delete_buffer(editor, &command),
+        "split" | "vsplit" => split(editor, &command.arg, command.name == "vsplit"),
+        "close" => close(editor),
+        "only" => only(editor),
+        "resize" => resize(editor, &command.arg, false),
+        "vertical" => vertical(editor, &command.arg)?,
+        "tabnew" | "tabedit" => {
+            editor.new_tab();
+            if !command.arg.is_empty() {
+                edit(editor, &command);
+            }
+        }
+        "tabnext" | "tabNext" | "tabprevious" => {
+            cycle_tabs(editor, &command.arg, command.name == "tabnext")
+        }
+        "tabclose" => {
+            if editor.num_tabs() == 1 {
+                editor.cmd_message = "Cannot close last tab page".to_string();
+                editor.fail();
+            } else {
+                editor.close_tab();
+            }
+        }
+        "tabonly" if editor.num_tabs() == 1 => {
+            editor.cmd_message = "Already only one tab page".to_string();
+        }
+        "tabonly" => {
+            editor.tab_pages.clear();
+            editor.tab = 0;
+        }
+        "buffers" | "files" | "ls" => list_buffers(editor),
+        "saveas" => save_as(editor, &command),
+        "earlier" => travel_in_time(editor, &command.arg, true),
+        "later" => travel_in_time(editor, &command.arg, false),
+        "delete" | "yank" => {
+            let op = if command.name == "delete" {
+                Operator::Delete
+            } else {
+                Operator::Yank
+            };
+            let register = command
+                .arg
+                .chars()
+                .next()
+                .filter(|&c| registers::is_valid_name(c));
+            operators::apply(editor, op, Range::lines(lines.0, lines.1), register);
+            normal_mode::normalize_fx(editor);
+        }
+        ">" | "<" => {
+            let op = if command.name == ">" {
+                Operator::ShiftRight
+            } else {
+                Operator::ShiftLeft
+            };
+            operators::apply(editor, op, Range::lines(lines.0, lines.1), None);
+        }
+        "substitute" | "&" => match substitute::parse(editor, &command.arg) {
+            Ok(substitute) => substitute::execute(editor, substitute, lines),
+            Err(message) => {
+                editor.cmd_message = message;
+                editor.fail();
+            }
+        },
+        "join" => {
+            let last = if lines.1 > lines.0 {
+                lines.1
+            } else {
+                (lines.0 + 1).min(editor.buffer.text.num_lines() - 1)
+            };
+            operators::join_lines(editor, lines.0, last);
+            normal_mode::normalize_fx(editor);
+        }
+        "nohlsearch" => editor.highlight_matches = false,
+        "set" | "setlocal" | "setglobal" => {
+            let scope = match command.name {
+                "setlocal" => Scope::Local,
+                "setglobal" => Scope::Global,
+                _ => Scope::Both,
+            };
+            set_options(editor, &command.arg, scope);
+        }
+        "source" if command.arg.is_empty() => {
+            editor.cmd_message = "Argument required".to_string();
+            editor.fail();
+        }
+        "source" => {
+            let result = config::source(editor, Path::new(&command.arg));
+            match result.map(|errors| errors.into_iter().next()) {
+                Ok(None) => {}
+                Ok(Some(message)) | Err(message) => {
+                    editor.cmd_message = message;
+                    editor.fail();
+                }
+            }
+        }
+        "trust" => {
+            let path = match command.arg.as_str() {
+                "" => ".virurc",
+                arg => arg,
+            };
+            match config::trust(Path::new(path)) {
+                Ok(()) => editor.cmd_message = format!("Trusted {}", path),
+                Err(message) => {
+                    editor.cmd_message = message;
+                    editor.fail();
+                }
+            }
+        }
+        name if mappings::is_command(name) => {
+            let leader = editor.options.mapleader().to_string();
+            match editor
+                .mappings
+                .command(name, command.bang, &command.arg, &leader)
+            {
+                Ok(shown) => editor.cmd_message = shown,
+                Err(message) => {
+                    editor.cmd_message = message;
+                    editor.fail();
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+// Closes the window, its tab page for the last window of one, or the
+// editor for the last window of all. The buffer of a closed window stays
+// loaded.
+fn quit(editor: &mut Editor, force: bool) -> Result<(), QuitError> {
+    if editor.layout.num_windows() > 1 {
+        editor.close_window(editor.layout.current);
+        return Ok(());
+    }
+    if editor.num_tabs() > 1 {
+        editor.close_tab();
+        return Ok(());
+    }
+    if editor.buffer.dirty && !force {
+        editor.cmd_message = "No write since last change (add ! to override)".to_string();
+        editor.fail();
+        return Ok(());
+    }
+    // Changes in hidden buffers would be lost as well.
+    if let Some(buffer) = editor.buffers.iter().find(|buffer| buffer.dirty) {
+        if !force {
+            editor.cmd_message = format!(
+                "No write since last change for buffer \"{}\" (add ! to override)",
+                buffer.name()
+            );
+            editor.fail();
+            return Ok(());
+        }
+    }
+    Err(QuitError {})
+}
+
+// Handles `:w [file]` with an optional range. Returns whether the lines
+// were written.
+fn write(editor: &mut Editor, command: &ExCommand) -> bool {
+    let all_lines = (0, editor.buffer.text.num_lines() - 1);
+    let lines = command.range.unwrap_or(all_lines);
+    let file_path = if command.arg.is_empty() {
+        editor.buffer.file_name.clone()
+    } else {
+        command.arg.clone()
+    };
+    if file_path.is_empty() {
+        editor.cmd_message = "No file name".to_string();
+        editor.fail();
+        return false;
+    }
+
+    let result = if file_path == editor.buffer.file_name {
+        if lines != all_lines && !command.bang {
+            editor.cmd_message = "Use ! to write partial buffer".to_string();
+            editor.fail();
+            return false;
+        }
+        if lines == all_lines {
+            editor.save_file()
+        } else {
+            editor.write_file(&file_path, lines)
+        }
+    } else {
+        if Path::new(&file_path).exists() && !command.bang {
+            editor.cmd_message = "File exists (add ! to override)".to_string();
+            editor.fail();
+            return false;
+        }
+        editor.write_file(&file_path, lines)
+    };
+
+    if result.is_err() {
+        editor.cmd_message = format!("Can't open file for writing: {}", file_path);
+        editor.fail();
+        return false;
+    }
+    true
+}
+
+// Handles `:e [file]`. Without a file the current one is read again, which
+// with `:e!` discards the changes. The buffer left keeps its changes.
+fn edit(editor: &mut Editor, command: &ExCommand) {
+    let file_path = if command.arg.is_empty() {
+        if editor.buffer.dirty && !command.bang {
+            editor.cmd_message = "No write since last change (add ! to override)".to_string();
+            editor.fail();
+            return;
+        }
+        if editor.buffer.file_name.is_empty() {
+            editor.cmd_message = "No file name".to_string();
+            editor.fail();
+            return;
+        }
+        let file_path = editor.buffer.file_name.clone();
+        editor.reload_buffer().map(|_| file_path)
+    } else {
+        editor.open_file(&command.arg).map(|_| command.arg.clone())
+    };
+
+    match file_path {
+        Ok(_) => show_file_info(editor),
+        Err(_) => {
+            editor.cmd_message = format!("Can't open file: {}", command.arg);
+            editor.fail();
+        }
+    }
+}
+
+fn show_file_info(editor: &mut Editor) {
+    editor.cmd_message = format!(
+        "\"{}\" {}L",
+        editor.buffer.name(),
+        editor.buffer.text.num_lines()
+    );
+}
+
+// Shows the buffer with the number, as `:b 2` or CTRL-^ do.
+pub fn edit_buffer(editor: &mut Editor, number: usize) {
+    if !editor.buffer_numbers().contains(&number) {
+        editor.cmd_message = format!("Buffer {} does not exist", number);
+        editor.fail();
+        return;
+    }
+    if number != editor.buffer.number {
+        editor.switch_to_buffer(number);
+        show_file_info(editor);
+    }
+}
+
+// Buffer given by its number or a part of its name, the current one when
+// no argument is given.
+fn find_buffer(editor: &Editor, arg: &str) -> Result<usize, String> {
+    if arg.is_empty() {
+        return Ok(editor.buffer.number);
+    }
+    if let Ok(number) = arg.parse() {
+        return Ok(number);
+    }
+
+    let matches: Vec<usize> = std::iter::once(&editor.buffer)
+        .chain(&editor.buffers)
+        .filter(|buffer| buffer.file_name.contains(arg))
+        .map(|buffer| buffer.number)
+        .collect();
+    match matches[..] {
+        [number] => Ok(number),
+        [] => Err(format!("No matching buffer for {}", arg)),
+        _ => Err(format!("More than one match for {}", arg)),
+    }
+}
+
+// Handles `:bnext [N]` and `:bprevious [N]`, which wrap around the end of
+// the buffer list.
+fn cycle_buffers(editor: &mut Editor, arg: &str, forward: bool) {
+    let count = match arg {
+        "" => 1,
+        arg => match arg.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                editor.cmd_message = format!("Invalid argument: {}", arg);
+                editor.fail();
+                return;
+            }
+        },
+    };
+
+    let numbers = editor.buffer_numbers();
+    let len = numbers.len();
+    let i = numbers
+        .iter()
+        .position(|&number| number == editor.buffer.number)
+        .unwrap();
+    let j = if forward {
+        (i + count % len) % len
+    } else {
+        (i + len - count % len) % len
+    };
+    edit_buffer(editor, numbers[j]);
+}
+
+// Handles `:bdelete [N]`. When the current buffer is deleted the alternate
+// one is shown, or an empty buffer if it was the last one.
+fn delete_buffer(editor: &mut Editor, command: &ExCommand) {
+    let number = match find_buffer(editor, &command.arg) {
+        Ok(number) if editor.buffer_numbers().contains(&number) => number,
+        Ok(number) => {
+            editor.cmd_message = format!("Buffer {} does not exist", number);
+            editor.fail();
+            return;
+        }
+        Err(message) => {
+            editor.cmd_message = message;
+            editor.fail();
+            return;
+        }
+    };
+
+    let buffer = std::iter::once(&editor.buffer)
+        .chain(&editor.buffers)
+        .find(|buffer| buffer.number == number)
+        .unwrap();
+    if buffer.dirty && !command.bang {
+        editor.cmd_message = format!(
+            "No write since last change for buffer {} (add ! to override)",
+            number
+        );
+        editor.fail();
+        return;
+    }
+
+    // The other windows showing the buffer are closed.
+    let shown: Vec<usize> = editor
+        .layout
+        .views
+        .iter()
+        .filter(|view| view.buffer == number)
+        .map(|view| view.id)
+        .collect();
+    for id in shown {
+        editor.close_window(id);
+    }
+
+    if number == editor.buffer.number {
+        let numbers = editor.buffer_numbers();
+        let next = editor
+            .alternate
+            .filter(|alternate| numbers.contains(alternate))
+            .or_else(|| numbers.iter().copied().find(|&other| other > number))
+            .or_else(|| numbers.iter().copied().rfind(|&other| other < number));
+        let next = match next {
+            Some(next) => next,
+            None => {
+                let next = editor.next_buffer_number();
+                let empty = Buffer::empty(next);
+                editor.buffers.push(empty);
+                next
+            }
+        };
+        editor.switch_to_buffer(next);
+    }
+
+    // The windows of the other tab pages show the current buffer instead.
+    for tab_page in editor.tab_pages.iter_mut() {
+        for view in tab_page.views.iter_mut() {
+            if view.buffer == number {
+                view.buffer = editor.buffer.number;
+                view.cursor = editor.buffer.last_cursor.clone();
+                view.offset = editor.buffer.last_offset.clone();
+            }
+        }
+    }
+
+    editor.buffers.retain(|buffer| buffer.number != number);
+    if editor.alternate == Some(number) {
+        editor.alternate = None;
+    }
+}
+
+// Handles `:ls`. Each buffer is shown with `%` for the current one, `#`
+// for the alternate one, `a` or `h` for shown in a window or hidden and `+` when
+// modified.
+fn list_buffers(editor: &mut Editor) {
+    let mut listed = vec![];
+
+    for number in editor.buffer_numbers() {
+        let current = number == editor.buffer.number;
+        let active = current || editor.shown_elsewhere(number);
+        let buffer = editor.buffer_in(number);
+        let line = match current {
+            true => editor.cursor.fy,
+            false => buffer.last_cursor.fy,
+        };
+
+        listed.push(format!(
+            "{}{}{}{} \"{}\" line {}",
+            number,
+            match (current, editor.alternate == Some(number)) {
+                (true, _) => "%",
+                (_, true) => "#",
+                _ => " ",
+            },
+            if active { "a" } else { "h" },
+            if buffer.dirty { "+" } else { " " },
+            buffer.name(),
+            line + 1
+        ));
+    }
+
+    editor.cmd_message = listed.join("  ");
+}
+
+// Handles `:tabnext [N]`, which goes to the tab page N, and `:tabprevious
+// [N]`, which goes N tab pages back. Both wrap around.
+pub fn cycle_tabs(editor: &mut Editor, arg: &str, forward: bool) {
+    let count = match arg {
+        "" => None,
+        arg => match arg.parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                editor.cmd_message = format!("Invalid argument: {}", arg);
+                editor.fail();
+                return;
+            }
+        },
+    };
+
+    let len = editor.num_tabs();
+    let i = match (count, forward) {
+        (Some(n), true) if (1..=len).contains(&n) => n - 1,
+        (Some(_), true) => {
+            editor.cmd_message = format!("Invalid argument: {}", arg);
+            editor.fail();
+            return;
+        }
+        (None, true) => (editor.tab + 1) % len,
+        (n, false) => (editor.tab + len - n.unwrap_or(1) % len) % len,
+    };
+    editor.goto_tab(i);
+}
+
+// Handles `:split [file]` and `:vsplit [file]`.
+fn split(editor: &mut Editor, arg: &str, vertical: bool) {
+    if let Err(message) = editor.split_window(vertical) {
+        editor.cmd_message = message;
+        editor.fail();
+        return;
+    }
+    if !arg.is_empty() {
+        match editor.open_file(arg) {
+            Ok(()) => show_file_info(editor),
+            Err(_) => {
+                editor.cmd_message = format!("Can't open file: {}", arg);
+                editor.fail();
+            }
+        }
+    }
+}
+
+fn close(editor: &mut Editor) {
+    if editor.layout.num_windows() == 1 {
+        editor.cmd_message = "Cannot close last window".to_string();
+        editor.fail();
+        return;
+    }
+    editor.close_window(editor.layout.current);
+}
+
+fn only(editor: &mut Editor) {
+    if editor.layout.num_windows() == 1 {
+        editor.cmd_message = "Already only one window".to_string();
+        return;
+    }
+    let others: Vec<usize> = editor.layout.views.iter().map(|view| view.id).collect();
+    for id in others {
+        editor.close_window(id);
+    }
+}
+
+// Handles `:resize [N]`, `:resize +N` and `:resize -N`. Without a number
+// the window is made as large as possible.
+fn resize(editor: &mut Editor, arg: &str, vertical: bool) {
+    let current = match vertical {
+        true => editor.window.num_cols,
+        false => editor.window.num_rows,
+    };
+    let size = match arg.split_at(arg.find(|c: char| c.is_ascii_digit()).unwrap_or(arg.len())) {
+        ("", "") => Some(usize::MAX),
+        ("", n) => n.parse().ok(),
+        ("+", n) => n.parse().ok().map(|n: usize| current + n),
+        ("-", n) => n.parse().ok().map(|n: usize| current.saturating_sub(n)),
+        _ => None,
+    };
+    match size {
+        Some(size) => editor.resize_window(vertical, size),
+        None => {
+            editor.cmd_message = format!("Invalid argument: {}", arg);
+            editor.fail();
+        }
+    }
+}
+
+// Handles `:vertical {command}`, which makes `:split` and `:resize` act
+// on the width.
+fn vertical(editor: &mut Editor, arg: &str) -> Result<(), QuitError> {
+    let command = match ex::parse(editor, arg) {
+        Ok(command) => command,
+        Err(message) => {
+            editor.cmd_message = message;
+            editor.fail();
+            return Ok(());
+        }
+    };
+    match command.name {
+        "split" => split(editor, &command.arg, true),
+        "resize" => resize(editor, &command.arg, true),
+        _ => return execute_command(editor, arg),
+    }
+    Ok(())
+}
+
+// Handles `:saveas {file}`, which writes the text to another file and
+// continues editing it.
+fn save_as(editor: &mut Editor, command: &ExCommand) {
+    if command.arg.is_empty() {
+        editor.cmd_message = "Argument required".to_string();
+        editor.fail();
+        return;
+    }
+    if Path::new(&command.arg).exists() && !command.bang {
+        editor.cmd_message = "File exists (add ! to override)".to_string();
+        editor.fail();
+        return;
+    }
+
+    let old_file_name = std::mem::replace(&mut editor.buffer.file_name, command.arg.clone());
+    if editor.save_file().is_err() {
+        editor.buffer.file_name = old_file_name;
+        editor.cmd_message = format!("Can't open file for writing: {}", command.arg);
+        editor.fail();
+        return;
+    }
+    editor.buffer.syntax_hl = highlight::get_syntax_highlighting(&command.arg);
+    editor.buffer.render_cache.clear();
+}
+
+// Handles `:earlier {N}` and `:later {N}`, where N is a number of changes or
+// a duration with one of the s, m, h, d suffixes.
+fn travel_in_time(editor: &mut Editor, arg: &str, backward: bool) {
+    let arg = if arg.is_empty() { "1" } else { arg };
+    let (number, unit) = match arg.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&arg[..i], &arg[i..]),
+        None => (arg, ""),
+    };
+
+    // Seconds in a unit, a number of changes counting as seconds.
+    let unit_seconds: Option<i64> = match unit {
+        "" | "s" => Some(1),
+        "m" => Some(60),
+        "h" => Some(60 * 60),
+        "d" => Some(60 * 60 * 24),
+        _ => None,
+    };
+    let count = number.parse::<i64>().ok().zip(unit_seconds);
+    let Some(count) = count.and_then(|(number, seconds)| number.checked_mul(seconds)) else {
+        editor.cmd_message = format!("Invalid argument: {}", arg);
+        editor.fail();
+        return;
+    };
+
+    let current = editor.buffer.history.current() as i64;
+    let target = match unit {
+        "" if backward => (current - count).max(0) as usize,
+        "" => current
+            .saturating_add(count)
+            .min(editor.buffer.history.num_states() as i64 - 1) as usize,
+        _ => editor
+            .buffer
+            .history
+            .state_by_time(if backward { count } else { -count }),
+    };
+
+    editor.goto_undo_state(target);
+}
+
+fn set_options(editor: &mut Editor, arg: &str, scope: Scope) {
+    let result = editor.options.set(arg, scope);
+    if editor.options.take_assigned().contains(&"hlsearch") {
+        editor.highlight_matches = true;
+    }
+    match result {
+        Ok(shown) => {
+            // The rendering depends on `tabstop`.
+            editor.buffer.render_cache.clear();
+            editor.cmd_message = shown;
+        }
+        Err(message) => {
+            editor.cmd_message = message;
+            editor.fail();
+        }
+    }
+}
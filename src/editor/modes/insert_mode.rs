@@ -1,19 +1,127 @@
 use super::super::Mode;
-use crate::editor::Editor;
+use crate::editor::operators;
+use crate::editor::repeat::{Command, RepeatableChange};
+use crate::editor::{char_start, render_x, Editor};
 use crate::terminal::input::Key;
 
+// Completes the change that entered insert mode. A count given to an
+// insert command repeats the typed text.
+fn finish_change(editor: &mut Editor, change: RepeatableChange) {
+    if let Command::Insert { key } = change.command {
+        for _ in 1..change.count.unwrap_or(1) {
+            if key == 'o' {
+                editor.add_blank_line(editor.cursor.fy + 1);
+                editor.cursor.fx = 0;
+                editor.cursor.fy += 1;
+            }
+            for &key in &change.inserted {
+                process_key_press(editor, key);
+            }
+        }
+    }
+
+    editor.last_change = Some(change);
+}
+
+// Length of the spaces and tabs ending the text.
+fn trailing_blanks(text: &str) -> usize {
+    text.len() - text.trim_end_matches([' ', '\t']).len()
+}
+
+// Inserts a tab, or with `expandtab` or `softtabstop` the whitespace up
+// to the next stop.
+fn insert_tab(editor: &mut Editor) {
+    let options = &editor.options;
+    if options.softtabstop() == 0 && !options.expandtab() {
+        editor.insert_char('\t');
+        return;
+    }
+
+    let (y, x) = (editor.cursor.fy, editor.cursor.fx);
+    let line = editor.buffer.text.line(y);
+    let stop = match options.softtabstop() {
+        0 => options.tabstop(),
+        width => width,
+    };
+    let target = (render_x(&line, x, options.tabstop()) / stop + 1) * stop;
+
+    // The blanks before the cursor are redone to use as many tabs as fit.
+    let start = if options.expandtab() {
+        x
+    } else {
+        x - trailing_blanks(&line[..x])
+    };
+    let blanks = options.whitespace(render_x(&line, start, options.tabstop()), target);
+
+    if start < x {
+        editor.delete_text((y, start), (y, x));
+    }
+    editor.insert_text(y, start, &blanks);
+    editor.cursor.fx = start + blanks.len();
+}
+
+// With `softtabstop`, deletes the blanks before the cursor back to the
+// previous stop. Returns false when there are no blanks before it.
+fn delete_soft_tab(editor: &mut Editor) -> bool {
+    let (y, x) = (editor.cursor.fy, editor.cursor.fx);
+    let line = editor.buffer.text.line(y);
+    let tabstop = editor.options.tabstop();
+    if trailing_blanks(&line[..x]) == 0 {
+        return false;
+    }
+
+    let column = render_x(&line, x, tabstop);
+    let target = (column - 1) / editor.options.softtabstop() * editor.options.softtabstop();
+    let mut start = x;
+    while start > x - trailing_blanks(&line[..x]) && render_x(&line, start, tabstop) > target {
+        start -= 1;
+    }
+
+    // A tab crossing the stop is replaced by the blanks up to it.
+    let blanks = editor
+        .options
+        .whitespace(render_x(&line, start, tabstop), target);
+    editor.delete_text((y, start), (y, x));
+    if !blanks.is_empty() {
+        editor.insert_text(y, start, &blanks);
+    }
+    editor.cursor.fx = start + blanks.len();
+    true
+}
+
 pub fn process_key_press(editor: &mut Editor, key: Key) {
+    if key != Key::Escape {
+        if let Some(change) = &mut editor.insert_change {
+            change.inserted.push(key);
+        }
+    }
+
     match key {
         Key::Escape => {
+            if let Some(change) = editor.insert_change.take() {
+                finish_change(editor, change);
+            }
             editor.mode = Mode::NORMAL;
+            if editor.block_insert.is_some() {
+                operators::finish_block_insert(editor);
+            } else {
+                let line = editor.buffer.text.line(editor.cursor.fy);
+                editor.cursor.fx = char_start(&line, editor.cursor.fx);
+            }
+            editor.buffer.history.end(&editor.cursor);
         }
         Key::Enter => {
             editor.break_line();
         }
-        Key::Char(c) => editor.insert_char(c as u8),
+        Key::Char(c) => editor.insert_char(c),
+        Key::Slash => editor.insert_char('/'),
+        Key::Colon => editor.insert_char(':'),
+        Key::Control('i') => insert_tab(editor),
         Key::Backspace => {
             if editor.cursor.fx > 0 {
-                editor.delete_previous_char()
+                if editor.options.softtabstop() == 0 || !delete_soft_tab(editor) {
+                    editor.delete_previous_char()
+                }
             } else if editor.cursor.fy > 0 {
                 editor.join_lines();
             }
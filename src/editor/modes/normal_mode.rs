@@ -1,136 +1,724 @@
 use super::super::Mode;
 use super::super::*;
 use super::*;
+use crate::editor::motions::{self, Motion};
+use crate::editor::operators::{self, Operator, Range};
+use crate::editor::registers;
+use crate::editor::repeat::{Command, OperatorTarget, RepeatableChange};
+use crate::editor::searching::{self, Search};
+use crate::editor::substitute::{self, Substitute};
 use crate::editor::Editor;
 use crate::terminal::input::Key;
 
 pub struct QuitError {}
 
-fn normalize_fx(editor: &mut Editor) {
-    editor.cursor.fx = editor.cursor.fx.min(
-        editor.lines[editor.cursor.fy]
-            .content
-            .len()
-            .saturating_sub(1),
-    );
+// Lines moved by CTRL-D and CTRL-U. A count is kept in `scroll` for the
+// next ones.
+fn scroll_amount(editor: &mut Editor, count: Option<usize>) -> usize {
+    if let Some(count) = count {
+        editor.options.set_scroll(count);
+    }
+    match editor.options.scroll() {
+        0 => editor.window.num_rows / 2,
+        lines => lines,
+    }
+}
+
+// Keeps the cursor on the last character of the line at most.
+pub fn normalize_fx(editor: &mut Editor) {
+    let line = editor.buffer.text.line(editor.cursor.fy);
+    if editor.cursor.fx >= line.len() {
+        editor.cursor.fx = char_start(&line, line.len());
+    }
+}
+
+pub fn move_cursor(editor: &mut Editor, motion: Motion, count: Option<usize>) {
+    if let Some((y, x)) = motions::target(editor, motion, count) {
+        editor.cursor.fy = y;
+        editor.cursor.fx = x;
+    } else {
+        editor.fail();
+    }
+
+    normalize_fx(editor);
 }
 
-fn move_cursor(editor: &mut Editor, key: Key) {
+pub fn key_to_char(key: Key) -> Option<char> {
     match key {
-        Key::Char('h') => {
-            if editor.cursor.fx > 0 {
-                editor.cursor.fx -= 1;
-            }
+        Key::Char(c) => Some(c),
+        Key::Slash => Some('/'),
+        Key::Colon => Some(':'),
+        _ => None,
+    }
+}
+
+// Motions starting with `g`, without the `g` itself.
+fn g_motion(key: Key) -> Option<Motion> {
+    match key {
+        Key::Char('g') => Some(Motion::FileStart),
+        Key::Char('e') => Some(Motion::WordEndBackward { big: false }),
+        Key::Char('E') => Some(Motion::WordEndBackward { big: true }),
+        _ => None,
+    }
+}
+
+// Reads a motion starting with `key`, together with its remaining keys.
+pub fn parse_motion(editor: &mut Editor, key: Key) -> Option<Motion> {
+    let motion = match key_to_char(key)? {
+        'h' => Motion::Left,
+        'j' => Motion::Down,
+        'k' => Motion::Up,
+        'l' => Motion::Right,
+        '0' => Motion::LineStart,
+        '$' => Motion::LineEnd,
+        '^' => Motion::FirstNonBlank,
+        'w' => Motion::WordForward { big: false },
+        'W' => Motion::WordForward { big: true },
+        'b' => Motion::WordBackward { big: false },
+        'B' => Motion::WordBackward { big: true },
+        'e' => Motion::WordEnd { big: false },
+        'E' => Motion::WordEnd { big: true },
+        'G' => Motion::FileEnd,
+        '}' => Motion::ParagraphForward,
+        '{' => Motion::ParagraphBackward,
+        '%' => Motion::MatchingBracket,
+        'H' => Motion::ScreenTop,
+        'M' => Motion::ScreenMiddle,
+        'L' => Motion::ScreenBottom,
+        'g' => g_motion(editor.read_key())?,
+        k @ ('f' | 'F' | 't' | 'T') => {
+            let motion = Motion::FindChar {
+                c: key_to_char(editor.read_key())?,
+                forward: k == 'f' || k == 't',
+                till: k == 't' || k == 'T',
+                repeat: false,
+            };
+            editor.last_find = Some(motion);
+            motion
+        }
+        k @ (';' | ',') => match editor.last_find? {
+            Motion::FindChar {
+                c, forward, till, ..
+            } => Motion::FindChar {
+                c,
+                forward: forward == (k == ';'),
+                till,
+                repeat: true,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(motion)
+}
+
+// Reads the count prefix of a command, returning it together with the
+// first key following it.
+pub fn read_count(editor: &mut Editor, key: Key) -> (Option<usize>, Key) {
+    let mut count: Option<usize> = None;
+    let mut key = key;
+
+    while let Key::Char(c @ '0'..='9') = key {
+        if c == '0' && count.is_none() {
+            break;
+        }
+        let digit = c.to_digit(10).unwrap() as usize;
+        count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+        key = editor.read_mapped_key();
+    }
+
+    (count, key)
+}
+
+// Counts given both before and after an operator (`3d2w`) multiply.
+fn multiply_counts(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        _ => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+// Reads the motion following an operator, together with its count.
+// Doubling the operator (`dd`, `gUU`, `gUgU`) selects the current line.
+fn read_operator_target(
+    editor: &mut Editor,
+    op: Operator,
+    op_key: char,
+) -> Option<(OperatorTarget, Option<usize>)> {
+    editor.mode = Mode::OPERATOR_PENDING;
+    let (motion_count, key) = {
+        let key = editor.read_mapped_key();
+        read_count(editor, key)
+    };
+    editor.mode = Mode::NORMAL;
+
+    let is_g_operator = matches!(
+        op,
+        Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase
+    );
+
+    let target = if key == Key::Char(op_key) {
+        OperatorTarget::Lines
+    } else if is_g_operator && key == Key::Char('g') {
+        match editor.read_key() {
+            k if k == Key::Char(op_key) => OperatorTarget::Lines,
+            k => OperatorTarget::Motion(g_motion(k)?),
         }
-        Key::Char('j') => {
-            if editor.cursor.fy + 1 < editor.lines.len() {
-                editor.cursor.fy += 1;
+    } else {
+        OperatorTarget::Motion(parse_motion(editor, key)?)
+    };
+
+    Some((target, motion_count))
+}
+
+fn execute(editor: &mut Editor, command: Command, count: Option<usize>, register: Option<char>) {
+    let n = count.unwrap_or(1).max(1);
+
+    match command {
+        Command::Operator { op, target } => {
+            let range = match target {
+                OperatorTarget::Lines => {
+                    let last_line =
+                        (editor.cursor.fy + n - 1).min(editor.buffer.text.num_lines() - 1);
+                    Some(Range::lines(editor.cursor.fy, last_line))
+                }
+                OperatorTarget::Motion(motion) => {
+                    operators::motion_range(editor, op, motion, count)
+                }
+            };
+
+            if let Some(range) = range {
+                operators::apply(editor, op, range, register);
+            }
+            if editor.mode != Mode::INSERT {
+                normalize_fx(editor);
             }
         }
-        Key::Char('k') => {
-            if editor.cursor.fy > 0 {
-                editor.cursor.fy -= 1;
+        Command::DeleteChars => {
+            if editor.buffer.text.line_len(editor.cursor.fy) > 0 {
+                if let Some(range) =
+                    operators::motion_range(editor, Operator::Delete, Motion::Right, count)
+                {
+                    operators::apply(editor, Operator::Delete, range, register);
+                }
+                normalize_fx(editor);
             }
         }
-        Key::Char('l') => {
-            if editor.cursor.fx + 1 < editor.lines[editor.cursor.fy].render.len() {
-                editor.cursor.fx += 1;
+        Command::Put { before } => {
+            operators::put(editor, register, before, n);
+        }
+        Command::Insert { key } => {
+            let len = editor.buffer.text.line_len(editor.cursor.fy);
+            match key {
+                'I' => editor.cursor.fx = 0,
+                'a' => {
+                    editor.cursor.fx =
+                        char_end(&editor.buffer.text.line(editor.cursor.fy), editor.cursor.fx)
+                }
+                'A' => editor.cursor.fx = len,
+                'o' => {
+                    editor.add_blank_line(editor.cursor.fy + 1);
+                    editor.cursor.fx = 0;
+                    editor.cursor.fy += 1;
+                }
+                _ => {}
             }
+            editor.mode = Mode::INSERT;
+        }
+    }
+}
+
+// Executes a command and remembers it for `.`. If the command enters insert
+// mode, the change is completed when insert mode is left.
+fn execute_change(editor: &mut Editor, change: RepeatableChange) {
+    execute(editor, change.command, change.count, change.register);
+
+    if editor.mode == Mode::INSERT {
+        editor.insert_change = Some(change);
+    } else if !matches!(
+        change.command,
+        Command::Operator {
+            op: Operator::Yank,
+            ..
+        }
+    ) {
+        editor.last_change = Some(change);
+    }
+}
+
+fn repeat_last_change(editor: &mut Editor, count: Option<usize>) {
+    let mut change = match editor.last_change.clone() {
+        Some(change) => change,
+        None => return,
+    };
+    if count.is_some() {
+        change.count = count;
+    }
+
+    let inserted = std::mem::take(&mut change.inserted);
+    execute_change(editor, change);
+
+    if editor.mode == Mode::INSERT {
+        for key in inserted {
+            insert_mode::process_key_press(editor, key);
         }
+        insert_mode::process_key_press(editor, Key::Escape);
+    }
+}
+
+// `q{register}` starts recording the typed keys, `q` stops it.
+fn toggle_recording(editor: &mut Editor) {
+    if let Some(mut recording) = editor.recording.take() {
+        // The command ending the recording, e.g. `q` or `"aq`, is not part
+        // of the macro.
+        recording.keys.truncate(recording.command_start);
+        let text = recording.keys.iter().map(|key| key.to_char()).collect();
+        editor.registers.record(recording.register, text);
+        editor.cmd_message.clear();
+        return;
+    }
+
+    match key_to_char(editor.read_key()) {
+        Some(name) if name.is_ascii_alphabetic() => {
+            editor.recording = Some(Recording {
+                register: name,
+                keys: vec![],
+                command_start: 0,
+            });
+            editor.cmd_message = format!("recording @{}", name);
+        }
+        _ => editor.fail(),
+    }
+}
+
+// `@{register}` executes the content of a register as typed keys, `@@` the
+// last executed register.
+fn execute_macro(editor: &mut Editor, count: usize) {
+    let name = match key_to_char(editor.read_key()) {
+        Some('@') => editor.last_macro,
+        Some(name) if registers::is_valid_name(name) => Some(name),
+        _ => None,
+    };
+    let content = name.and_then(|name| editor.get_register(name));
+    let (name, content) = match (name, content) {
+        (Some(name), Some(content)) => (name, content),
         _ => {
-            panic!("internal error");
+            editor.fail();
+            return;
         }
+    };
+    editor.last_macro = Some(name);
+
+    let mut keys: Vec<Key> = content
+        .text
+        .chars()
+        .map(|c| {
+            if c == '\n' {
+                Key::Enter
+            } else {
+                Key::from_char(c)
+            }
+        })
+        .collect();
+    if content.kind == registers::RegisterKind::Linewise {
+        keys.push(Key::Enter);
     }
+    editor.feed_keys(&keys.repeat(count), true);
+}
 
-    normalize_fx(editor);
+// Runs the searches one after another, `count` times. The cursor does not
+// move if one of them fails.
+fn run_searches(editor: &mut Editor, searches: &[Search], count: usize) -> bool {
+    let orig_cursor = editor.cursor.clone();
+
+    for _ in 0..count {
+        for search in searches {
+            if let Err(message) = searching::search(editor, search) {
+                editor.cursor = orig_cursor;
+                editor.cmd_message = message;
+                editor.fail();
+                return false;
+            }
+        }
+    }
+
+    if let Some(search) = searches.last() {
+        editor.highlight_matches = true;
+        editor.search_count = searching::count_matches(editor, &search.pattern);
+    }
+    true
 }
 
-pub fn process_key_press(editor: &mut Editor, key: Key) -> Result<(), QuitError> {
+// `/` and `?` read a search, moving the cursor to the matches while it is
+// typed.
+fn search_prompt(editor: &mut Editor, forward: bool, count: usize) {
     let orig_cursor = editor.cursor.clone();
+    let mut preview = |editor: &mut Editor, input: &str| {
+        editor.cursor = orig_cursor.clone();
+        if !editor.options.incsearch() {
+            return;
+        }
+        if let Ok(searches) = searching::parse(editor, input, forward) {
+            let _ = searches
+                .iter()
+                .try_for_each(|search| searching::search(editor, search));
+        }
+    };
 
-    match key {
-        Key::Char(k) if ['h', 'j', 'k', 'l'].contains(&k) => {
-            move_cursor(editor, key);
+    let prompt = if forward { "/" } else { "?" };
+    let maybe_input = command_mode::enter_command(editor, prompt, Some(&mut preview));
+    editor.cursor = orig_cursor;
+
+    let searches = match maybe_input.map(|input| searching::parse(editor, &input, forward)) {
+        Some(Ok(searches)) => searches,
+        Some(Err(message)) => {
+            editor.cmd_message = message;
+            editor.fail();
+            return;
         }
-        Key::Char('0') => {
-            editor.cursor.fx = 0;
+        None => return,
+    };
+
+    let last = searches.last().unwrap();
+    editor.last_pattern = Some(last.pattern.clone());
+    editor.search_forward = last.forward;
+    editor.search_offset = last.offset;
+    run_searches(editor, &searches, count);
+}
+
+// Start and end of the identifier under the cursor, or of the first one
+// after it in the line.
+fn word_under_cursor(editor: &Editor) -> Option<(usize, usize)> {
+    let line = editor.buffer.text.line(editor.cursor.fy);
+
+    // Words are those of `\<` and `\>`, so that the pattern built matches.
+    let start = line[editor.cursor.fx..]
+        .char_indices()
+        .find(|&(_, c)| regex::is_word_char(c))?
+        .0
+        + editor.cursor.fx;
+    let start = line[..start]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| regex::is_word_char(c))
+        .last()
+        .map_or(start, |(i, _)| i);
+    let end = line[start..]
+        .char_indices()
+        .find(|&(_, c)| !regex::is_word_char(c))
+        .map_or(line.len(), |(i, _)| start + i);
+
+    Some((start, end))
+}
+
+// `*` and `#` search for the identifier under the cursor as a whole word,
+// `g*` and `g#` also inside other words.
+fn search_word(editor: &mut Editor, forward: bool, whole_word: bool, count: usize) {
+    let (start, end) = match word_under_cursor(editor) {
+        Some(word) => word,
+        None => {
+            editor.cmd_message = "No identifier under cursor".to_string();
+            editor.fail();
+            return;
         }
-        Key::Char('$') => {
-            editor.cursor.fx = editor.lines[editor.cursor.fy]
-                .render
-                .len()
-                .saturating_sub(1)
+    };
+
+    let word = &editor.buffer.text.line(editor.cursor.fy)[start..end];
+    let pattern = if whole_word {
+        format!("\\<{}\\>", word)
+    } else {
+        word.to_string()
+    };
+
+    editor.last_pattern = Some(pattern.clone());
+    editor.search_forward = forward;
+    editor.search_offset = searching::Offset::default();
+
+    // The search starts at the beginning of the word, so that `#` skips it.
+    let orig_fx = editor.cursor.fx;
+    editor.cursor.fx = start;
+    let search = Search {
+        pattern,
+        forward,
+        offset: editor.search_offset,
+    };
+    if !run_searches(editor, &[search], count) {
+        editor.cursor.fx = orig_fx;
+    }
+}
+
+// CTRL-W commands, acting on the windows. The count is the size given to
+// the resize commands.
+fn window_command(editor: &mut Editor, count: Option<usize>) -> Result<(), QuitError> {
+    let n = count.unwrap_or(1).max(1);
+
+    match editor.read_key() {
+        Key::Char(k @ ('s' | 'S' | 'v')) | Key::Control(k @ ('s' | 'v')) => {
+            if let Err(message) = editor.split_window(k == 'v') {
+                editor.cmd_message = message;
+                editor.fail();
+            }
         }
-        Key::Char('i') => {
-            editor.mode = Mode::INSERT;
+        Key::Char(k @ ('h' | 'j' | 'k' | 'l')) | Key::Control(k @ ('h' | 'j' | 'k' | 'l')) => {
+            for _ in 0..n {
+                let rect = editor.layout.rect(editor.layout.current);
+                let position = (
+                    rect.y + editor.cursor.fy - editor.offset.y,
+                    rect.x + editor.cursor.rx - editor.offset.x,
+                );
+                match editor.layout.neighbor(k, position) {
+                    Some(id) => editor.goto_window(id),
+                    None => break,
+                }
+            }
         }
-        Key::Char('I') => {
-            editor.cursor.fx = 0;
-            editor.mode = Mode::INSERT;
+        // With a count, the window with that number from the top left.
+        Key::Char(k @ ('w' | 'W')) | Key::Control(k @ 'w') => {
+            let ids: Vec<usize> = editor.layout.rects().iter().map(|&(id, _)| id).collect();
+            let i = ids
+                .iter()
+                .position(|&id| id == editor.layout.current)
+                .unwrap();
+            let j = match count {
+                Some(count) => (count.max(1) - 1).min(ids.len() - 1),
+                None if k == 'W' => (i + ids.len() - 1) % ids.len(),
+                None => (i + 1) % ids.len(),
+            };
+            editor.goto_window(ids[j]);
         }
-        Key::Char('a') => {
-            editor.mode = Mode::INSERT;
-            editor.cursor.fx += 1;
+        Key::Char('c') => command_mode::execute_command(editor, "close")?,
+        Key::Char('q') | Key::Control('q') => command_mode::execute_command(editor, "quit")?,
+        Key::Char('o') | Key::Control('o') => command_mode::execute_command(editor, "only")?,
+        Key::Char('=') => {
+            editor.layout.equalize();
+            editor.update_window_size();
         }
-        Key::Char('A') => {
-            editor.mode = Mode::INSERT;
-            editor.cursor.fx = editor.lines[editor.cursor.fy].content.len();
+        Key::Char('+') => editor.resize_window(false, editor.window.num_rows + n),
+        Key::Char('-') => editor.resize_window(false, editor.window.num_rows.saturating_sub(n)),
+        Key::Char('>') => editor.resize_window(true, editor.window.num_cols + n),
+        Key::Char('<') => editor.resize_window(true, editor.window.num_cols.saturating_sub(n)),
+        // Without a count, as high or as wide as possible.
+        Key::Char('_') => editor.resize_window(false, count.unwrap_or(usize::MAX)),
+        Key::Char('|') => editor.resize_window(true, count.unwrap_or(usize::MAX)),
+        _ => editor.fail(),
+    }
+    Ok(())
+}
+
+fn g_operator(key: Key) -> Option<(Operator, char)> {
+    match key {
+        Key::Char('u') => Some((Operator::Lowercase, 'u')),
+        Key::Char('U') => Some((Operator::Uppercase, 'U')),
+        Key::Char('~') => Some((Operator::ToggleCase, '~')),
+        _ => None,
+    }
+}
+
+pub fn process_key_press(editor: &mut Editor, key: Key) -> Result<(), QuitError> {
+    let orig_cursor = editor.cursor.clone();
+    editor.buffer.history.begin(&editor.cursor);
+    editor.search_count = None;
+
+    let (mut count, mut key) = read_count(editor, key);
+    let mut register = None;
+
+    // A register is given as `"x` before or after the count.
+    if key == Key::Char('"') {
+        match key_to_char(editor.read_key()).filter(|&c| registers::is_valid_name(c)) {
+            Some(name) => {
+                register = Some(name);
+                let (second_count, next_key) = {
+                    let key = editor.read_key();
+                    read_count(editor, key)
+                };
+                count = multiply_counts(count, second_count);
+                key = next_key;
+            }
+            None => key = Key::Escape,
         }
-        Key::Char('o') => {
-            editor.mode = Mode::INSERT;
-            editor.add_blank_line(editor.cursor.fy + 1);
-            editor.cursor.fx = 0;
-            editor.cursor.fy += 1;
+    }
+    let n = count.unwrap_or(1).max(1);
+
+    let change = |command| RepeatableChange {
+        command,
+        count,
+        register,
+        inserted: vec![],
+    };
+
+    match key {
+        Key::Char(k @ ('i' | 'I' | 'a' | 'A' | 'o')) => {
+            execute_change(editor, change(Command::Insert { key: k }));
         }
         Key::Char('x') => {
-            editor.delete_current_char();
+            execute_change(editor, change(Command::DeleteChars));
         }
-        Key::Control('d') => {
-            editor.cursor.fy = (editor.cursor.fy + 30).min(editor.lines.len().saturating_sub(1));
-            normalize_fx(editor);
+        Key::Char(k @ ('p' | 'P')) => {
+            execute_change(editor, change(Command::Put { before: k == 'P' }));
         }
-        Key::Control('u') => {
-            editor.cursor.fy = editor.cursor.fy.saturating_sub(30);
+        Key::Char('.') => {
+            repeat_last_change(editor, count);
+        }
+        Key::Char('u') => {
+            if !(0..n).all(|_| editor.undo()) {
+                editor.cmd_message = "Already at oldest change".to_string();
+            }
             normalize_fx(editor);
         }
-        Key::Slash => {
-            let maybe_pattern =
-                command_mode::enter_command(editor, "/", Some(searching::forward_search));
-
-            if let Some(pattern) = maybe_pattern {
-                editor.last_pattern = Some(pattern);
-            } else {
-                editor.cursor = orig_cursor;
+        Key::Control('r') => {
+            if !(0..n).all(|_| editor.redo()) {
+                editor.cmd_message = "Already at newest change".to_string();
             }
+            normalize_fx(editor);
         }
-        Key::Char('n') => {
-            if let Some(pattern) = editor.last_pattern.clone() {
-                move_cursor(editor, Key::Char('l'));
-                if !searching::forward_search(editor, &pattern) {
-                    editor.cursor = orig_cursor;
+        Key::Char('g') => match editor.read_key() {
+            // `{count}gt` goes to the tab page given by the count.
+            Key::Char('t') => {
+                let arg = count.map_or(String::new(), |count| count.to_string());
+                command_mode::cycle_tabs(editor, &arg, true);
+            }
+            Key::Char('T') => command_mode::cycle_tabs(editor, &n.to_string(), false),
+            Key::Char('-') => {
+                editor.goto_undo_state(editor.buffer.history.current().saturating_sub(n));
+            }
+            // `g&` repeats the last substitution with its flags on all lines,
+            // using the last search pattern.
+            Key::Char('&') => match editor.last_substitute.clone() {
+                Some(last) => {
+                    let substitute = Substitute {
+                        pattern: editor.last_pattern.clone().unwrap_or(last.pattern),
+                        ..last
+                    };
+                    let last_line = editor.buffer.text.num_lines() - 1;
+                    substitute::execute(editor, substitute, (0, last_line));
+                }
+                None => {
+                    editor.cmd_message = "No previous substitute regular expression".to_string();
+                    editor.fail();
                 }
+            },
+            Key::Char(k @ ('*' | '#')) => search_word(editor, k == '*', false, n),
+            Key::Char('+') => {
+                let last = editor.buffer.history.num_states() - 1;
+                editor.goto_undo_state((editor.buffer.history.current() + n).min(last));
             }
-        }
-        Key::Char('N') => {
-            if let Some(pattern) = editor.last_pattern.clone() {
-                move_cursor(editor, Key::Char('h'));
-                if !searching::backward_search(editor, &pattern) {
-                    editor.cursor = orig_cursor;
+            key => {
+                if let Some((op, op_key)) = g_operator(key) {
+                    if let Some((target, motion_count)) = read_operator_target(editor, op, op_key) {
+                        let count = multiply_counts(count, motion_count);
+                        let command = Command::Operator { op, target };
+                        execute_change(
+                            editor,
+                            RepeatableChange {
+                                count,
+                                ..change(command)
+                            },
+                        );
+                    } else {
+                        editor.fail();
+                    }
+                } else if let Some(motion) = g_motion(key) {
+                    move_cursor(editor, motion, count);
+                } else {
+                    editor.fail();
                 }
             }
+        },
+        Key::Char(k @ ('d' | 'c' | 'y' | '>' | '<')) => {
+            let op = match k {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                'y' => Operator::Yank,
+                '>' => Operator::ShiftRight,
+                _ => Operator::ShiftLeft,
+            };
+            if let Some((target, motion_count)) = read_operator_target(editor, op, k) {
+                let count = multiply_counts(count, motion_count);
+                let command = Command::Operator { op, target };
+                execute_change(
+                    editor,
+                    RepeatableChange {
+                        count,
+                        ..change(command)
+                    },
+                );
+            } else {
+                editor.fail();
+            }
+        }
+        Key::Char('v') => visual_mode::start(editor, Mode::VISUAL),
+        Key::Char('V') => visual_mode::start(editor, Mode::VISUAL_LINE),
+        Key::Control('v') => visual_mode::start(editor, Mode::VISUAL_BLOCK),
+        // `&` repeats the last substitution on the current line, without its
+        // flags.
+        Key::Char('&') => {
+            command_mode::execute_command(editor, "s")?;
+        }
+        Key::Char(k @ ('*' | '#')) => search_word(editor, k == '*', true, n),
+        Key::Char('q') => toggle_recording(editor),
+        Key::Char('@') => execute_macro(editor, n),
+        // CTRL-^ shows the alternate buffer, or the buffer given by the count.
+        Key::Char('\x1e') => match count.or(editor.alternate) {
+            Some(number) => command_mode::edit_buffer(editor, number),
+            None => {
+                editor.cmd_message = "No alternate file".to_string();
+                editor.fail();
+            }
+        },
+        Key::Control('w') => window_command(editor, count)?,
+        Key::Control('d') => {
+            let amount = scroll_amount(editor, count);
+            editor.cursor.fy =
+                (editor.cursor.fy + amount).min(editor.buffer.text.num_lines().saturating_sub(1));
+            normalize_fx(editor);
         }
+        Key::Control('u') => {
+            let amount = scroll_amount(editor, count);
+            editor.cursor.fy = editor.cursor.fy.saturating_sub(amount);
+            normalize_fx(editor);
+        }
+        Key::Slash => search_prompt(editor, true, n),
+        Key::Char('?') => search_prompt(editor, false, n),
+        Key::Char(k @ ('n' | 'N')) => match editor.last_pattern.clone() {
+            Some(pattern) => {
+                let search = Search {
+                    pattern,
+                    forward: editor.search_forward == (k == 'n'),
+                    offset: editor.search_offset,
+                };
+                run_searches(editor, &[search], n);
+            }
+            None => {
+                editor.cmd_message = "No previous regular expression".to_string();
+                editor.fail();
+            }
+        },
         Key::Colon => {
+            // A count gives the range of lines from the cursor on.
+            if let Some(n) = count {
+                let range = format!(".,.+{}", n - 1);
+                let keys: Vec<Key> = range.chars().map(Key::from_char).collect();
+                editor.feed_keys(&keys, false);
+            }
             let maybe_command = command_mode::enter_command(editor, ":", None);
             if let Some(command) = maybe_command {
-                if command == "q" {
-                    return Err(QuitError {});
-                }
-                if command == "w" {
-                    editor.save_file();
-                }
+                command_mode::execute_command(editor, &command)?;
+            }
+        }
+        _ => {
+            if let Some(motion) = parse_motion(editor, key) {
+                move_cursor(editor, motion, count);
+            } else {
+                editor.fail();
             }
         }
-        _ => {}
+    }
+
+    // Insert mode closes the change when it is left, so that the whole
+    // session is undone at once.
+    if editor.mode != Mode::INSERT {
+        editor.buffer.history.end(&editor.cursor);
     }
 
     Ok(())
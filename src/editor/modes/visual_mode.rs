@@ -0,0 +1,301 @@
+use super::super::Mode;
+use super::normal_mode;
+use crate::editor::operators::{self, Block, Operator, Range};
+use crate::editor::registers;
+use crate::editor::{char_end, render_x, render_x_to_fx, Editor};
+use crate::terminal::input::Key;
+
+pub fn start(editor: &mut Editor, mode: Mode) {
+    editor.visual_start = (editor.cursor.fy, editor.cursor.fx);
+    editor.mode = mode;
+}
+
+// `v`, `V` and Ctrl-v switch to another kind of selection, or end it if it
+// is already of that kind.
+fn switch_mode(editor: &mut Editor, mode: Mode) {
+    editor.mode = if editor.mode == mode {
+        Mode::NORMAL
+    } else {
+        mode
+    };
+}
+
+// Ends of the selection in text order.
+fn selection(editor: &Editor) -> ((usize, usize), (usize, usize)) {
+    let cursor = (editor.cursor.fy, editor.cursor.fx);
+    (
+        editor.visual_start.min(cursor),
+        editor.visual_start.max(cursor),
+    )
+}
+
+// Block with the start of the selection and the cursor in its corners.
+pub fn block(editor: &Editor) -> Block {
+    let columns = |(y, x): (usize, usize)| {
+        let line = editor.buffer.text.line(y);
+        let first = render_x(&line, x, editor.options.tabstop());
+        let last = render_x(&line, char_end(&line, x), editor.options.tabstop()).max(first + 1) - 1;
+        (first, last)
+    };
+
+    let (start, end) = selection(editor);
+    let a = columns(editor.visual_start);
+    let b = columns((editor.cursor.fy, editor.cursor.fx));
+
+    Block {
+        top: start.0,
+        bottom: end.0,
+        left: a.0.min(b.0),
+        right: a.1.max(b.1),
+    }
+}
+
+// Render columns of the row covered by the selection, the end excluded.
+// The column after the end of the line stands for the line break.
+pub fn selected_columns(editor: &Editor, row: usize) -> Option<(usize, usize)> {
+    if !editor.mode.is_visual() {
+        return None;
+    }
+
+    let (start, end) = selection(editor);
+    if row < start.0 || row > end.0 {
+        return None;
+    }
+
+    let line = editor.buffer.text.line(row);
+    let width = render_x(&line, line.len(), editor.options.tabstop());
+    let columns = match editor.mode {
+        Mode::VISUAL_LINE => (0, width.max(1)),
+        Mode::VISUAL_BLOCK => {
+            let block = block(editor);
+            (block.left, (block.right + 1).min(width))
+        }
+        _ => {
+            let from = if row == start.0 {
+                render_x(&line, start.1, editor.options.tabstop())
+            } else {
+                0
+            };
+            let to = if row == end.0 && end.1 < line.len() {
+                render_x(&line, char_end(&line, end.1), editor.options.tabstop())
+            } else {
+                width + 1
+            };
+            (from, to)
+        }
+    };
+
+    Some(columns)
+}
+
+// Parts of the lines covered by the selection, without line breaks.
+fn line_segments(editor: &Editor) -> Vec<(usize, usize, usize)> {
+    let (start, end) = selection(editor);
+    let block = block(editor);
+
+    (start.0..=end.0)
+        .map(|y| {
+            let line = editor.buffer.text.line(y);
+            let (from, to) = match editor.mode {
+                Mode::VISUAL_LINE => (0, line.len()),
+                Mode::VISUAL_BLOCK => block.line_bounds(editor, y),
+                _ => {
+                    let from = if y == start.0 {
+                        start.1.min(line.len())
+                    } else {
+                        0
+                    };
+                    let to = if y == end.0 {
+                        char_end(&line, end.1)
+                    } else {
+                        line.len()
+                    };
+                    (from, to)
+                }
+            };
+            (y, from, to)
+        })
+        .collect()
+}
+
+// Range of a characterwise selection. It includes the line break when the
+// selection ends at the end of a line.
+fn charwise_range(editor: &Editor) -> Range {
+    let (start, end) = selection(editor);
+    let line = editor.buffer.text.line(end.0);
+
+    let to = if end.1 >= line.len() && end.0 + 1 < editor.buffer.text.num_lines() {
+        (end.0 + 1, 0)
+    } else {
+        (end.0, char_end(&line, end.1))
+    };
+
+    Range {
+        from: (start.0, start.1.min(editor.buffer.text.line_len(start.0))),
+        to,
+        linewise: false,
+    }
+}
+
+fn apply_operator(editor: &mut Editor, op: Operator, register: Option<char>, count: usize) {
+    let (start, end) = selection(editor);
+    let mode = editor.mode;
+    editor.mode = Mode::NORMAL;
+
+    match op {
+        // Shifts always act on whole lines, a count shifts several times.
+        Operator::ShiftRight | Operator::ShiftLeft => {
+            for _ in 0..count {
+                operators::apply(editor, op, Range::lines(start.0, end.0), register);
+            }
+        }
+        _ if mode == Mode::VISUAL_BLOCK => {
+            let block = block(editor);
+            operators::apply_block(editor, op, &block, register);
+        }
+        _ if mode == Mode::VISUAL_LINE => {
+            operators::apply(editor, op, Range::lines(start.0, end.0), register);
+        }
+        _ => {
+            let range = charwise_range(editor);
+            operators::apply(editor, op, range, register);
+        }
+    }
+
+    if editor.mode != Mode::INSERT {
+        normal_mode::normalize_fx(editor);
+    }
+}
+
+// `r` replaces every selected character.
+fn replace_chars(editor: &mut Editor, c: char) {
+    let segments = line_segments(editor);
+    editor.mode = Mode::NORMAL;
+
+    for &(y, from, to) in &segments {
+        let text = editor.get_text((y, from), (y, to));
+        let replaced: String = text.chars().map(|_| c).collect();
+        if replaced != text {
+            editor.delete_text((y, from), (y, to));
+            editor.insert_text(y, from, &replaced);
+        }
+    }
+
+    editor.cursor.fy = segments[0].0;
+    editor.cursor.fx = segments[0].1;
+    normal_mode::normalize_fx(editor);
+}
+
+// `o` moves the cursor to the other end of the selection. In a block `O`
+// moves it to the other corner in the same line.
+fn swap_ends(editor: &mut Editor, same_line: bool) {
+    let cursor = (editor.cursor.fy, editor.cursor.fx);
+    let start = editor.visual_start;
+
+    if same_line {
+        let cursor_column = render_x(
+            &editor.buffer.text.line(cursor.0),
+            cursor.1,
+            editor.options.tabstop(),
+        );
+        let start_column = render_x(
+            &editor.buffer.text.line(start.0),
+            start.1,
+            editor.options.tabstop(),
+        );
+        editor.cursor.fx = render_x_to_fx(
+            &editor.buffer.text.line(cursor.0),
+            start_column,
+            editor.options.tabstop(),
+        );
+        editor.visual_start.1 = render_x_to_fx(
+            &editor.buffer.text.line(start.0),
+            cursor_column,
+            editor.options.tabstop(),
+        );
+    } else {
+        editor.cursor.fy = start.0;
+        editor.cursor.fx = start.1;
+        editor.visual_start = cursor;
+    }
+    normal_mode::normalize_fx(editor);
+}
+
+pub fn process_key_press(editor: &mut Editor, key: Key) {
+    editor.buffer.history.begin(&editor.cursor);
+    let orig_selection = selection(editor);
+
+    let (count, mut key) = normal_mode::read_count(editor, key);
+    let mut register = None;
+
+    if key == Key::Char('"') {
+        match normal_mode::key_to_char(editor.read_key()).filter(|&c| registers::is_valid_name(c)) {
+            Some(name) => {
+                register = Some(name);
+                key = editor.read_key();
+            }
+            None => key = Key::Escape,
+        }
+    }
+    let n = count.unwrap_or(1).max(1);
+
+    match key {
+        Key::Escape => editor.mode = Mode::NORMAL,
+        Key::Char('v') => switch_mode(editor, Mode::VISUAL),
+        Key::Char('V') => switch_mode(editor, Mode::VISUAL_LINE),
+        Key::Control('v') => switch_mode(editor, Mode::VISUAL_BLOCK),
+        Key::Char('o') => swap_ends(editor, false),
+        Key::Char('O') => swap_ends(editor, editor.mode == Mode::VISUAL_BLOCK),
+        Key::Char(k @ ('d' | 'x' | 'c' | 's' | 'y' | '>' | '<' | '~' | 'u' | 'U')) => {
+            let op = match k {
+                'd' | 'x' => Operator::Delete,
+                'c' | 's' => Operator::Change,
+                'y' => Operator::Yank,
+                '>' => Operator::ShiftRight,
+                '<' => Operator::ShiftLeft,
+                'u' => Operator::Lowercase,
+                'U' => Operator::Uppercase,
+                _ => Operator::ToggleCase,
+            };
+            apply_operator(editor, op, register, n);
+        }
+        Key::Char('J') => {
+            let (start, end) = selection(editor);
+            let last = end
+                .0
+                .max(start.0 + 1)
+                .min(editor.buffer.text.num_lines() - 1);
+            editor.mode = Mode::NORMAL;
+            operators::join_lines(editor, start.0, last);
+            normal_mode::normalize_fx(editor);
+        }
+        Key::Char('r') => match normal_mode::key_to_char(editor.read_key()) {
+            Some(c) => replace_chars(editor, c),
+            None => editor.mode = Mode::NORMAL,
+        },
+        // Ex commands act on the selected lines.
+        Key::Colon => {
+            editor.mode = Mode::NORMAL;
+            let keys: Vec<Key> = ":'<,'>".chars().map(Key::from_char).collect();
+            editor.feed_keys(&keys, false);
+        }
+        Key::Char(k @ ('I' | 'A')) if editor.mode == Mode::VISUAL_BLOCK => {
+            let block = block(editor);
+            editor.mode = Mode::NORMAL;
+            operators::block_insert(editor, &block, k == 'A');
+        }
+        _ => match normal_mode::parse_motion(editor, key) {
+            Some(motion) => normal_mode::move_cursor(editor, motion, count),
+            None => editor.fail(),
+        },
+    }
+
+    if !editor.mode.is_visual() {
+        editor.buffer.visual_marks = Some(orig_selection);
+    }
+
+    // Insert mode closes the change when it is left.
+    if editor.mode != Mode::INSERT {
+        editor.buffer.history.end(&editor.cursor);
+    }
+}
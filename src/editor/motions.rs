@@ -0,0 +1,477 @@
+use super::highlight;
+use super::{char_end, char_start, render_x, render_x_to_fx, Editor};
+
+// How the text between the cursor and the target of a motion is treated
+// by an operator.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    LineStart,
+    LineEnd,
+    FirstNonBlank,
+    WordForward {
+        big: bool,
+    },
+    WordBackward {
+        big: bool,
+    },
+    WordEnd {
+        big: bool,
+    },
+    WordEndBackward {
+        big: bool,
+    },
+    FileStart,
+    FileEnd,
+    ParagraphForward,
+    ParagraphBackward,
+    MatchingBracket,
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
+    // `f`, `F`, `t` and `T`. Repeating a till motion with `;` or `,` does
+    // not stop at a character right next to the cursor.
+    FindChar {
+        c: char,
+        forward: bool,
+        till: bool,
+        repeat: bool,
+    },
+}
+
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match self {
+            Motion::Up
+            | Motion::Down
+            | Motion::FileStart
+            | Motion::FileEnd
+            | Motion::ScreenTop
+            | Motion::ScreenMiddle
+            | Motion::ScreenBottom => MotionKind::Linewise,
+            Motion::LineEnd
+            | Motion::WordEnd { .. }
+            | Motion::WordEndBackward { .. }
+            | Motion::MatchingBracket => MotionKind::Inclusive,
+            Motion::FindChar { forward, .. } => {
+                if *forward {
+                    MotionKind::Inclusive
+                } else {
+                    MotionKind::Exclusive
+                }
+            }
+            _ => MotionKind::Exclusive,
+        }
+    }
+}
+
+// Position of the cursor after repeating the motion `count` times, or None
+// if the motion fails (e.g. the searched character is not in the line).
+// For some motions the count has a different meaning, e.g. `5G` goes to
+// the fifth line.
+pub fn target(editor: &Editor, motion: Motion, count: Option<usize>) -> Option<(usize, usize)> {
+    let (y, x) = (editor.cursor.fy, editor.cursor.fx);
+    let last_line = editor.buffer.text.num_lines() - 1;
+    let n = count.unwrap_or(1).max(1);
+
+    let res = match motion {
+        Motion::Up => {
+            let target = y.checked_sub(1).map(|_| y.saturating_sub(n))?;
+            (target, same_column(editor, (y, x), target))
+        }
+        Motion::Down => {
+            let target = (y < last_line).then_some((y + n).min(last_line))?;
+            (target, same_column(editor, (y, x), target))
+        }
+        Motion::LineEnd => {
+            let y = (y + n - 1).min(last_line);
+            let line = editor.buffer.text.line(y);
+            (y, char_start(&line, line.len()))
+        }
+        Motion::FileStart | Motion::FileEnd => {
+            let y = match count {
+                Some(n) => (n.max(1) - 1).min(last_line),
+                None if motion == Motion::FileStart => 0,
+                None => last_line,
+            };
+            (y, first_non_blank(editor, y))
+        }
+        Motion::ScreenTop => screen_line(editor, 0, n - 1),
+        Motion::ScreenMiddle => screen_line(editor, 1, 0),
+        Motion::ScreenBottom => screen_line(editor, 2, n - 1),
+        _ => {
+            let mut pos = (y, x);
+            for i in 0..n {
+                pos = step(editor, motion, pos, i > 0)?;
+            }
+            pos
+        }
+    };
+
+    Some(res)
+}
+
+// Byte of the line `target` drawn in the same column as the cursor.
+fn same_column(editor: &Editor, (y, x): (usize, usize), target: usize) -> usize {
+    let column = render_x(&editor.buffer.text.line(y), x, editor.options.tabstop());
+    render_x_to_fx(
+        &editor.buffer.text.line(target),
+        column,
+        editor.options.tabstop(),
+    )
+}
+
+// Single application of a motion that can be repeated by a count.
+fn step(
+    editor: &Editor,
+    motion: Motion,
+    (y, x): (usize, usize),
+    repeated: bool,
+) -> Option<(usize, usize)> {
+    let res = match motion {
+        Motion::Left => (y, char_start(&editor.buffer.text.line(y), x)),
+        Motion::Right => (y, char_end(&editor.buffer.text.line(y), x)),
+        Motion::LineStart => (y, 0),
+        Motion::FirstNonBlank => (y, first_non_blank(editor, y)),
+        Motion::WordForward { big } => word_forward(editor, (y, x), big),
+        Motion::WordBackward { big } => word_backward(editor, (y, x), big),
+        Motion::WordEnd { big } => word_end(editor, (y, x), big),
+        Motion::WordEndBackward { big } => word_end_backward(editor, (y, x), big),
+        Motion::ParagraphForward => paragraph(editor, y, true),
+        Motion::ParagraphBackward => paragraph(editor, y, false),
+        Motion::MatchingBracket => matching_bracket(editor, (y, x))?,
+        Motion::FindChar {
+            c,
+            forward,
+            till,
+            repeat,
+        } => find_char(editor, (y, x), c, forward, till, repeat || repeated)?,
+        _ => panic!("internal error"),
+    };
+
+    Some(res)
+}
+
+pub fn first_non_blank(editor: &Editor, y: usize) -> usize {
+    let line = editor.buffer.text.line(y);
+    line.find(|c: char| c != ' ' && c != '\t')
+        .unwrap_or(line.len())
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Blank,
+    Punctuation,
+    Word,
+}
+
+fn char_class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if big || !c.is_ascii() || highlight::is_alphanumeric(c as u8) {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+// Walks over the text one character at a time. The end of every line is
+// a position of its own, seen as a '\n' character.
+struct Walker<'a> {
+    editor: &'a Editor,
+    y: usize,
+    x: usize,
+    line: String,
+}
+
+impl<'a> Walker<'a> {
+    fn new(editor: &'a Editor, y: usize, x: usize) -> Self {
+        Self {
+            editor,
+            y,
+            x,
+            line: editor.buffer.text.line(y),
+        }
+    }
+
+    fn pos(&self) -> (usize, usize) {
+        (self.y, self.x)
+    }
+
+    fn set(&mut self, (y, x): (usize, usize)) {
+        if y != self.y {
+            self.line = self.editor.buffer.text.line(y);
+        }
+        self.y = y;
+        self.x = x;
+    }
+
+    fn char(&self) -> char {
+        self.line[self.x..].chars().next().unwrap_or('\n')
+    }
+
+    fn class(&self, big: bool) -> CharClass {
+        char_class(self.char(), big)
+    }
+
+    fn on_empty_line(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    fn forward(&mut self) -> bool {
+        if self.x < self.line.len() {
+            self.x = char_end(&self.line, self.x);
+        } else if self.y + 1 < self.editor.buffer.text.num_lines() {
+            self.set((self.y + 1, 0));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn backward(&mut self) -> bool {
+        if self.x > 0 {
+            self.x = char_start(&self.line, self.x);
+        } else if self.y > 0 {
+            let len = self.editor.buffer.text.line_len(self.y - 1);
+            self.set((self.y - 1, len));
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+fn word_forward(editor: &Editor, (y, x): (usize, usize), big: bool) -> (usize, usize) {
+    let mut w = Walker::new(editor, y, x);
+    let start = w.pos();
+
+    let class = w.class(big);
+    if class != CharClass::Blank {
+        while w.class(big) == class {
+            if !w.forward() {
+                return w.pos();
+            }
+        }
+    }
+
+    // Empty lines count as words.
+    while w.class(big) == CharClass::Blank && !(w.on_empty_line() && w.pos() != start) {
+        if !w.forward() {
+            break;
+        }
+    }
+    w.pos()
+}
+
+pub fn word_end(editor: &Editor, (y, x): (usize, usize), big: bool) -> (usize, usize) {
+    let mut w = Walker::new(editor, y, x);
+
+    if !w.forward() {
+        return w.pos();
+    }
+    while w.class(big) == CharClass::Blank {
+        if !w.forward() {
+            return w.pos();
+        }
+    }
+
+    let class = w.class(big);
+    loop {
+        let pos = w.pos();
+        if !w.forward() || w.class(big) != class {
+            return pos;
+        }
+    }
+}
+
+// Last character of the word under the cursor.
+pub fn current_word_end(editor: &Editor, (y, x): (usize, usize), big: bool) -> (usize, usize) {
+    let mut w = Walker::new(editor, y, x);
+
+    let class = w.class(big);
+    loop {
+        let pos = w.pos();
+        if !w.forward() || w.class(big) != class || pos.0 != w.pos().0 {
+            return pos;
+        }
+    }
+}
+
+fn word_backward(editor: &Editor, (y, x): (usize, usize), big: bool) -> (usize, usize) {
+    let mut w = Walker::new(editor, y, x);
+
+    if !w.backward() {
+        return w.pos();
+    }
+    while w.class(big) == CharClass::Blank && !w.on_empty_line() {
+        if !w.backward() {
+            return w.pos();
+        }
+    }
+    if w.on_empty_line() {
+        return w.pos();
+    }
+
+    let class = w.class(big);
+    loop {
+        let pos = w.pos();
+        if !w.backward() || w.class(big) != class {
+            return pos;
+        }
+    }
+}
+
+fn word_end_backward(editor: &Editor, (y, x): (usize, usize), big: bool) -> (usize, usize) {
+    let mut w = Walker::new(editor, y, x);
+
+    let class = w.class(big);
+    if class == CharClass::Blank {
+        if !w.backward() {
+            return w.pos();
+        }
+    } else {
+        while w.class(big) == class {
+            if !w.backward() {
+                return w.pos();
+            }
+        }
+    }
+
+    while w.class(big) == CharClass::Blank && !w.on_empty_line() {
+        if !w.backward() {
+            return w.pos();
+        }
+    }
+    w.pos()
+}
+
+// Moves to the next (or previous) empty line after the current paragraph.
+fn paragraph(editor: &Editor, y: usize, forward: bool) -> (usize, usize) {
+    let num_lines = editor.buffer.text.num_lines();
+    let is_empty = |y: usize| editor.buffer.text.line_len(y) == 0;
+    let step = |y: usize| {
+        if forward {
+            (y + 1 < num_lines).then_some(y + 1)
+        } else {
+            y.checked_sub(1)
+        }
+    };
+
+    let mut y = y;
+    while is_empty(y) {
+        match step(y) {
+            Some(next) => y = next,
+            None => break,
+        }
+    }
+    while !is_empty(y) {
+        match step(y) {
+            Some(next) => y = next,
+            None => {
+                let x = if forward {
+                    editor.buffer.text.line_len(y)
+                } else {
+                    0
+                };
+                return (y, x);
+            }
+        }
+    }
+    (y, 0)
+}
+
+fn matching_bracket(editor: &Editor, (y, x): (usize, usize)) -> Option<(usize, usize)> {
+    let pairs = editor.options.matchpairs();
+
+    let line = editor.buffer.text.line(y);
+    let (x, c) = line
+        .char_indices()
+        .skip_while(|&(i, _)| i < x)
+        .find(|&(_, c)| pairs.iter().any(|&(open, close)| c == open || c == close))?;
+
+    let (open, close) = *pairs
+        .iter()
+        .find(|&&(open, close)| c == open || c == close)
+        .unwrap();
+    let forward = c == open;
+
+    let mut w = Walker::new(editor, y, x);
+    let mut depth = 0;
+    loop {
+        let c = w.char();
+        if c == open {
+            depth += if forward { 1 } else { -1 };
+        } else if c == close {
+            depth += if forward { -1 } else { 1 };
+        }
+        if depth == 0 {
+            return Some(w.pos());
+        }
+
+        let moved = if forward { w.forward() } else { w.backward() };
+        if !moved {
+            return None;
+        }
+    }
+}
+
+// Top, middle or bottom line of the visible part of the text, moved
+// `skip` lines towards the middle.
+fn screen_line(editor: &Editor, which: usize, skip: usize) -> (usize, usize) {
+    let first = editor.offset.y;
+    let last = (editor.offset.y + editor.window.num_rows)
+        .min(editor.buffer.text.num_lines())
+        .saturating_sub(1);
+
+    let y = match which {
+        0 => (first + skip).min(last),
+        1 => (first + last) / 2,
+        _ => last.saturating_sub(skip).max(first),
+    };
+    (y, first_non_blank(editor, y))
+}
+
+fn find_char(
+    editor: &Editor,
+    (y, x): (usize, usize),
+    c: char,
+    forward: bool,
+    till: bool,
+    repeat: bool,
+) -> Option<(usize, usize)> {
+    let line = editor.buffer.text.line(y);
+
+    // A repeated till motion would not move if the character is adjacent.
+    let skip = till && repeat;
+
+    if forward {
+        let mut from = char_end(&line, x);
+        if skip {
+            from = char_end(&line, from);
+        }
+        let found = from + line[from..].find(c)?;
+        Some((
+            y,
+            if till {
+                char_start(&line, found)
+            } else {
+                found
+            },
+        ))
+    } else {
+        let to = if skip { char_start(&line, x) } else { x };
+        let found = line[..to].rfind(c)?;
+        Some((y, if till { char_end(&line, found) } else { found }))
+    }
+}
@@ -0,0 +1,547 @@
+use super::motions::{self, Motion, MotionKind};
+use super::registers::{Register, RegisterKind};
+use super::{char_end, char_start, render_x, render_x_to_fx, Editor, Mode};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+}
+
+// Text an operator acts on. For charwise ranges `to` is exclusive, for
+// linewise ones only the lines matter and both ends are included.
+pub struct Range {
+    pub from: (usize, usize),
+    pub to: (usize, usize),
+    pub linewise: bool,
+}
+
+impl Range {
+    pub fn lines(from: usize, to: usize) -> Self {
+        Self {
+            from: (from, 0),
+            to: (to, 0),
+            linewise: true,
+        }
+    }
+}
+
+// Rectangle of text selected in blockwise visual mode, given by its lines
+// and render columns, all included.
+pub struct Block {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Block {
+    // Bytes of the line `y` inside the block, the end excluded.
+    pub fn line_bounds(&self, editor: &Editor, y: usize) -> (usize, usize) {
+        let line = editor.buffer.text.line(y);
+        (
+            render_x_to_fx(&line, self.left, editor.options.tabstop()),
+            render_x_to_fx(&line, self.right + 1, editor.options.tabstop()),
+        )
+    }
+}
+
+// Text inserted with `I`, `A` or `c` in a block is typed in its first line
+// and copied to the other lines when insert mode is left.
+pub struct BlockInsert {
+    start: (usize, usize),
+    // Line, byte and number of spaces to add before the text, for lines
+    // too short to reach the block.
+    lines: Vec<(usize, usize, usize)>,
+}
+
+// Range covered by moving the cursor with `motion`, following the special
+// cases of Vim for exclusive motions.
+pub fn motion_range(
+    editor: &Editor,
+    op: Operator,
+    motion: Motion,
+    count: Option<usize>,
+) -> Option<Range> {
+    let cursor = (editor.cursor.fy, editor.cursor.fx);
+
+    let on_blank = editor.buffer.text.line(cursor.0)[cursor.1..]
+        .chars()
+        .next()
+        .is_none_or(char::is_whitespace);
+    // `cw` behaves like `ce` but stays within the current word.
+    if let (Operator::Change, Motion::WordForward { big }, false) = (op, motion, on_blank) {
+        let mut end = motions::current_word_end(editor, cursor, big);
+        for _ in 1..count.unwrap_or(1) {
+            end = motions::word_end(editor, end, big);
+        }
+        return Some(charwise_range(editor, cursor, end, true));
+    }
+
+    let mut target = motions::target(editor, motion, count)?;
+    let mut kind = motion.kind();
+
+    // The last word moved over by `w` ends the range, not the next line.
+    if let Motion::WordForward { .. } = motion {
+        if target.0 > cursor.0 && target.1 <= motions::first_non_blank(editor, target.0) {
+            target = (target.0 - 1, editor.buffer.text.line_len(target.0 - 1));
+        }
+    }
+
+    if kind == MotionKind::Exclusive && target.0 > cursor.0 && target.1 == 0 {
+        if cursor.1 <= motions::first_non_blank(editor, cursor.0) {
+            kind = MotionKind::Linewise;
+            target.0 -= 1;
+        } else {
+            target = (target.0 - 1, editor.buffer.text.line_len(target.0 - 1));
+        }
+    }
+
+    if kind == MotionKind::Linewise {
+        let (from, to) = (cursor.0.min(target.0), cursor.0.max(target.0));
+        return Some(Range::lines(from, to));
+    }
+
+    Some(charwise_range(
+        editor,
+        cursor.min(target),
+        cursor.max(target),
+        kind == MotionKind::Inclusive,
+    ))
+}
+
+fn charwise_range(
+    editor: &Editor,
+    from: (usize, usize),
+    to: (usize, usize),
+    inclusive: bool,
+) -> Range {
+    let mut to = to;
+    if inclusive {
+        let line = editor.buffer.text.line(to.0);
+        to.1 = char_end(&line, to.1);
+    }
+
+    Range {
+        from,
+        to,
+        linewise: false,
+    }
+}
+
+pub fn apply(editor: &mut Editor, op: Operator, range: Range, register: Option<char>) {
+    match op {
+        Operator::Delete => {
+            let content = yank(editor, &range);
+            if store(editor, op, register, content) {
+                delete(editor, &range);
+            }
+        }
+        Operator::Change => {
+            let content = yank(editor, &range);
+            if store(editor, op, register, content) {
+                change(editor, &range);
+            }
+        }
+        Operator::Yank => {
+            let content = yank(editor, &range);
+            if store(editor, op, register, content) {
+                editor.cursor.fy = range.from.0;
+                if !range.linewise {
+                    editor.cursor.fx = range.from.1;
+                }
+            }
+        }
+        Operator::ShiftRight | Operator::ShiftLeft => {
+            for y in range.from.0..=range.to.0 {
+                shift_line(editor, y, op == Operator::ShiftRight);
+            }
+            editor.cursor.fy = range.from.0;
+            editor.cursor.fx = motions::first_non_blank(editor, range.from.0);
+        }
+        Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+            let (from, to) = charwise_bounds(editor, &range);
+            let text = editor.get_text(from, to);
+            let converted: String = text.chars().map(|c| convert_case(c, op)).collect();
+
+            if converted != text {
+                editor.delete_text(from, to);
+                editor.insert_text(from.0, from.1, &converted);
+            }
+            editor.cursor.fy = from.0;
+            editor.cursor.fx = from.1;
+        }
+    }
+}
+
+// Writes yanked or deleted text to the register. Fails for the read-only
+// registers, in which case the text must be left alone.
+fn store(editor: &mut Editor, op: Operator, register: Option<char>, content: Register) -> bool {
+    let result = if op == Operator::Yank {
+        editor.registers.yank(register, content)
+    } else {
+        editor.registers.delete(register, content)
+    };
+
+    if let Err(message) = result {
+        editor.cmd_message = message;
+        editor.fail();
+        return false;
+    }
+    true
+}
+
+// Bounds of the range with line ranges spanning whole lines, without the
+// final newline.
+fn charwise_bounds(editor: &Editor, range: &Range) -> ((usize, usize), (usize, usize)) {
+    if range.linewise {
+        (
+            (range.from.0, 0),
+            (range.to.0, editor.buffer.text.line_len(range.to.0)),
+        )
+    } else {
+        (range.from, range.to)
+    }
+}
+
+fn yank(editor: &Editor, range: &Range) -> Register {
+    let (from, to) = charwise_bounds(editor, range);
+    Register {
+        text: editor.get_text(from, to),
+        kind: if range.linewise {
+            RegisterKind::Linewise
+        } else {
+            RegisterKind::Charwise
+        },
+    }
+}
+
+fn delete(editor: &mut Editor, range: &Range) {
+    if !range.linewise {
+        editor.delete_text(range.from, range.to);
+        editor.cursor.fy = range.from.0;
+        editor.cursor.fx = range.from.1;
+        return;
+    }
+
+    let (first, last) = (range.from.0, range.to.0);
+    if last + 1 < editor.buffer.text.num_lines() {
+        editor.delete_text((first, 0), (last + 1, 0));
+    } else if first > 0 {
+        let end = editor.buffer.text.line_len(last);
+        editor.delete_text(
+            (first - 1, editor.buffer.text.line_len(first - 1)),
+            (last, end),
+        );
+    } else {
+        let end = editor.buffer.text.line_len(last);
+        editor.delete_text((0, 0), (last, end));
+    }
+
+    editor.cursor.fy = first.min(editor.buffer.text.num_lines() - 1);
+    editor.cursor.fx = motions::first_non_blank(editor, editor.cursor.fy);
+}
+
+fn change(editor: &mut Editor, range: &Range) {
+    let (from, to) = charwise_bounds(editor, range);
+    editor.delete_text(from, to);
+
+    editor.cursor.fy = from.0;
+    editor.cursor.fx = from.1;
+    editor.mode = Mode::INSERT;
+}
+
+pub fn apply_block(editor: &mut Editor, op: Operator, block: &Block, register: Option<char>) {
+    let bounds: Vec<(usize, usize)> = (block.top..=block.bottom)
+        .map(|y| block.line_bounds(editor, y))
+        .collect();
+    let lines = (block.top..=block.bottom).zip(bounds.iter().copied());
+
+    match op {
+        Operator::Delete | Operator::Change | Operator::Yank => {
+            let text: Vec<String> = lines
+                .clone()
+                .map(|(y, (from, to))| editor.get_text((y, from), (y, to)))
+                .collect();
+            let content = Register {
+                text: text.join("\n"),
+                kind: RegisterKind::Blockwise,
+            };
+
+            if !store(editor, op, register, content) {
+                return;
+            }
+            if op != Operator::Yank {
+                for (y, (from, to)) in lines {
+                    if from < to {
+                        editor.delete_text((y, from), (y, to));
+                    }
+                }
+            }
+
+            editor.cursor.fy = block.top;
+            editor.cursor.fx = bounds[0].0;
+            if op == Operator::Change {
+                block_insert(editor, block, false);
+            }
+        }
+        Operator::ShiftRight | Operator::ShiftLeft => {
+            apply(editor, op, Range::lines(block.top, block.bottom), register);
+        }
+        Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+            for (y, (from, to)) in lines {
+                let text = editor.get_text((y, from), (y, to));
+                let converted: String = text.chars().map(|c| convert_case(c, op)).collect();
+                if converted != text {
+                    editor.delete_text((y, from), (y, to));
+                    editor.insert_text(y, from, &converted);
+                }
+            }
+            editor.cursor.fy = block.top;
+            editor.cursor.fx = bounds[0].0;
+        }
+    }
+}
+
+// Starts inserting before the block, or after it with `append`. Lines not
+// reaching the block are skipped, or padded with spaces when appending.
+pub fn block_insert(editor: &mut Editor, block: &Block, append: bool) {
+    let column = if append { block.right + 1 } else { block.left };
+
+    let mut lines = vec![];
+    for y in block.top..=block.bottom {
+        let line = editor.buffer.text.line(y);
+        let width = render_x(&line, line.len(), editor.options.tabstop());
+        if width < column {
+            if append {
+                lines.push((y, line.len(), column - width));
+            }
+        } else {
+            lines.push((
+                y,
+                render_x_to_fx(&line, column, editor.options.tabstop()),
+                0,
+            ));
+        }
+    }
+    if lines.is_empty() {
+        return;
+    }
+
+    let (y, x, padding) = lines.remove(0);
+    editor.insert_text(y, x, &" ".repeat(padding));
+    editor.cursor.fy = y;
+    editor.cursor.fx = x + padding;
+    editor.mode = Mode::INSERT;
+
+    editor.block_insert = Some(BlockInsert {
+        start: (editor.cursor.fy, editor.cursor.fx),
+        lines,
+    });
+}
+
+// Copies the text typed in the first line of the block to the others. It
+// is skipped if the cursor left the line.
+pub fn finish_block_insert(editor: &mut Editor) {
+    let insert = match editor.block_insert.take() {
+        Some(insert) => insert,
+        None => return,
+    };
+
+    let (y, x) = insert.start;
+    if editor.cursor.fy != y || editor.cursor.fx < x {
+        let line = editor.buffer.text.line(editor.cursor.fy);
+        editor.cursor.fx = char_start(&line, editor.cursor.fx);
+        return;
+    }
+
+    let text = editor.get_text((y, x), (y, editor.cursor.fx));
+    if !text.is_empty() {
+        for (y, x, padding) in insert.lines {
+            editor.insert_text(y, x, &(" ".repeat(padding) + &text));
+        }
+    }
+    editor.cursor.fy = y;
+    editor.cursor.fx = x;
+}
+
+// Joins the lines from `first` to `last` like `J`. The indent of a joined
+// line is replaced by a single space, which is left out before ')' and
+// after white space.
+pub fn join_lines(editor: &mut Editor, first: usize, last: usize) {
+    for _ in first..last {
+        let line = editor.buffer.text.line(first);
+        let next = editor.buffer.text.line(first + 1);
+        let indent = next.len() - next.trim_start_matches([' ', '\t']).len();
+        let rest = &next[indent..];
+
+        let x = line.len();
+        editor.delete_text((first, x), (first + 1, indent));
+        if !(line.is_empty()
+            || line.ends_with([' ', '\t'])
+            || rest.is_empty()
+            || rest.starts_with(')'))
+        {
+            editor.insert_text(first, x, " ");
+        }
+
+        editor.cursor.fy = first;
+        editor.cursor.fx = x;
+    }
+}
+
+// Inserts the content of a register `count` times after the cursor, or
+// before it for `P`. Linewise text goes below or above the current line.
+pub fn put(editor: &mut Editor, register: Option<char>, before: bool, count: usize) {
+    let name = register.unwrap_or('"');
+    let content = match editor.get_register(name) {
+        Some(content) => content,
+        None => {
+            editor.cmd_message = format!("Nothing in register {}", name);
+            editor.fail();
+            return;
+        }
+    };
+
+    if content.kind == RegisterKind::Blockwise {
+        put_block(editor, &content.text, before, count);
+        return;
+    }
+
+    let y = editor.cursor.fy;
+    if content.kind == RegisterKind::Linewise {
+        let text = vec![content.text.as_str(); count].join("\n");
+        let first_line = if before {
+            editor.insert_text(y, 0, &(text + "\n"));
+            y
+        } else {
+            editor.insert_text(
+                y,
+                editor.buffer.text.line_len(y),
+                &("\n".to_string() + &text),
+            );
+            y + 1
+        };
+        editor.cursor.fy = first_line;
+        editor.cursor.fx = motions::first_non_blank(editor, first_line);
+        return;
+    }
+
+    let text = content.text.repeat(count);
+    if text.is_empty() {
+        return;
+    }
+    let mut x = editor.cursor.fx.min(editor.buffer.text.line_len(y));
+    if !before {
+        x = char_end(&editor.buffer.text.line(y), x);
+    }
+    editor.insert_text(y, x, &text);
+
+    // The cursor ends on the last inserted character, unless the text spans
+    // several lines.
+    if !text.contains('\n') {
+        editor.cursor.fx = x + char_start(&text, text.len());
+    } else {
+        editor.cursor.fx = x;
+    }
+}
+
+// Puts the lines of a block at the same column of the following lines,
+// adding lines at the end of the text and padding short ones if needed.
+fn put_block(editor: &mut Editor, text: &str, before: bool, count: usize) {
+    let first_line = editor.cursor.fy;
+    let line = editor.buffer.text.line(first_line);
+    let x = if before {
+        editor.cursor.fx.min(line.len())
+    } else {
+        char_end(&line, editor.cursor.fx)
+    };
+    let column = render_x(&line, x, editor.options.tabstop());
+
+    let pieces: Vec<&str> = text.split('\n').collect();
+    let width = pieces
+        .iter()
+        .map(|piece| render_x(piece, piece.len(), editor.options.tabstop()))
+        .max()
+        .unwrap_or(0);
+
+    for (i, piece) in pieces.iter().enumerate() {
+        let y = first_line + i;
+        if y == editor.buffer.text.num_lines() {
+            editor.add_blank_line(y);
+        }
+
+        let line = editor.buffer.text.line(y);
+        let line_width = render_x(&line, line.len(), editor.options.tabstop());
+        let (x, mut text) = if line_width < column {
+            (line.len(), " ".repeat(column - line_width))
+        } else {
+            (
+                render_x_to_fx(&line, column, editor.options.tabstop()),
+                String::new(),
+            )
+        };
+
+        // Pieces are padded to the width of the block, unless nothing
+        // follows them.
+        let padded = piece.to_string()
+            + &" ".repeat(width - render_x(piece, piece.len(), editor.options.tabstop()));
+        text += &padded.repeat(count - 1);
+        if x < line.len() {
+            text += &padded;
+        } else {
+            text += piece;
+        }
+        if !text.is_empty() {
+            editor.insert_text(y, x, &text);
+        }
+    }
+
+    editor.cursor.fy = first_line;
+    editor.cursor.fx = render_x_to_fx(
+        &editor.buffer.text.line(first_line),
+        column,
+        editor.options.tabstop(),
+    );
+}
+
+// Moves the line by one `shiftwidth`, rebuilding its indent with tabs
+// and spaces as the options say.
+fn shift_line(editor: &mut Editor, y: usize, right: bool) {
+    let line = editor.buffer.text.line(y);
+    if line.is_empty() {
+        return;
+    }
+
+    let len = line.len() - line.trim_start_matches([' ', '\t']).len();
+    let width = render_x(&line, len, editor.options.tabstop());
+    let shift = editor.options.shift_width();
+    let new_width = if right {
+        width + shift
+    } else {
+        width.saturating_sub(shift)
+    };
+
+    let indent = editor.options.whitespace(0, new_width);
+    if indent != line[..len] {
+        if len > 0 {
+            editor.delete_text((y, 0), (y, len));
+        }
+        editor.insert_text(y, 0, &indent);
+    }
+}
+
+fn convert_case(c: char, op: Operator) -> String {
+    match op {
+        Operator::Lowercase => c.to_lowercase().collect(),
+        Operator::Uppercase => c.to_uppercase().collect(),
+        _ if c.is_uppercase() => c.to_lowercase().collect(),
+        _ => c.to_uppercase().collect(),
+    }
+}
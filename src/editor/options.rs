@@ -0,0 +1,702 @@
+// Options changed with `:set`. Global options have a single value, local
+// ones also have a value of their own for the buffer, which `:setlocal`
+// changes alone.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Bool,
+    Number,
+    String,
+    // Comma separated items.
+    List,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(usize),
+    String(String),
+}
+
+// Which values `:set`, `:setlocal` and `:setglobal` change.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    Both,
+    Local,
+    Global,
+}
+
+struct Definition {
+    name: &'static str,
+    short: &'static str,
+    kind: Kind,
+    local: bool,
+    default: fn() -> Value,
+}
+
+const DEFINITIONS: [Definition; 15] = [
+    // Ignore case in search patterns.
+    Definition {
+        name: "ignorecase",
+        short: "ic",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(false),
+    },
+    // Do not ignore case when the pattern has uppercase letters.
+    Definition {
+        name: "smartcase",
+        short: "scs",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(false),
+    },
+    // Highlight the matches of the last search pattern.
+    Definition {
+        name: "hlsearch",
+        short: "hls",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(true),
+    },
+    // Move to the match while the search pattern is typed.
+    Definition {
+        name: "incsearch",
+        short: "is",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(true),
+    },
+    // Searches wrap around the end of the text.
+    Definition {
+        name: "wrapscan",
+        short: "ws",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(true),
+    },
+    // Columns between tab stops.
+    Definition {
+        name: "tabstop",
+        short: "ts",
+        kind: Kind::Number,
+        local: true,
+        default: || Value::Number(4),
+    },
+    // Columns of an indent step of `>` and `<`. Zero uses `tabstop`.
+    Definition {
+        name: "shiftwidth",
+        short: "sw",
+        kind: Kind::Number,
+        local: true,
+        default: || Value::Number(4),
+    },
+    // Columns of a <Tab> or <BS> in insert mode. Zero uses real tabs.
+    Definition {
+        name: "softtabstop",
+        short: "sts",
+        kind: Kind::Number,
+        local: true,
+        default: || Value::Number(0),
+    },
+    // Insert spaces instead of tabs.
+    Definition {
+        name: "expandtab",
+        short: "et",
+        kind: Kind::Bool,
+        local: true,
+        default: || Value::Bool(false),
+    },
+    // Lines moved by CTRL-D and CTRL-U. Zero moves half the window.
+    Definition {
+        name: "scroll",
+        short: "scr",
+        kind: Kind::Number,
+        local: false,
+        default: || Value::Number(0),
+    },
+    // Pairs of characters `%` jumps between, like `(:)`.
+    Definition {
+        name: "matchpairs",
+        short: "mps",
+        kind: Kind::List,
+        local: true,
+        default: || Value::String("(:),{:},[:]".to_string()),
+    },
+    // Read a trusted `.virurc` in the current directory at startup.
+    Definition {
+        name: "exrc",
+        short: "ex",
+        kind: Kind::Bool,
+        local: false,
+        default: || Value::Bool(false),
+    },
+    // Milliseconds to wait for the rest of a mapping.
+    Definition {
+        name: "timeoutlen",
+        short: "tm",
+        kind: Kind::Number,
+        local: false,
+        default: || Value::Number(1000),
+    },
+    // Keys `<leader>` stands for in mappings.
+    Definition {
+        name: "mapleader",
+        short: "mapleader",
+        kind: Kind::String,
+        local: false,
+        default: || Value::String("\\".to_string()),
+    },
+    // Flags leaving out messages: `I` for the greeting at startup.
+    Definition {
+        name: "shortmess",
+        short: "shm",
+        kind: Kind::String,
+        local: false,
+        default: || Value::String(String::new()),
+    },
+];
+
+fn find(name: &str) -> Option<usize> {
+    DEFINITIONS
+        .iter()
+        .position(|def| name == def.name || name == def.short)
+}
+
+// Splits the arguments of `:set` at whitespace not escaped with `\`.
+fn split_args(arg: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut chars = arg.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => item.push(chars.next().unwrap_or('\\')),
+            c if c.is_whitespace() => {
+                if !item.is_empty() {
+                    items.push(std::mem::take(&mut item));
+                }
+            }
+            c => item.push(c),
+        }
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+    items
+}
+
+// Widest indent and tab stop, so that a tab or an indent cannot take
+// gigabytes of columns or spaces.
+const MAX_WIDTH: usize = 9999;
+
+// Checks the value an option is about to get.
+fn check(name: &str, value: &Value) -> Result<(), String> {
+    match (name, value) {
+        ("tabstop", Value::Number(0)) => Err("Argument must be positive: tabstop=0".to_string()),
+        ("tabstop" | "shiftwidth" | "softtabstop", Value::Number(n)) if *n > MAX_WIDTH => {
+            Err(format!("Invalid argument: {}={}", name, n))
+        }
+        ("matchpairs", Value::String(pairs)) => {
+            let valid = pairs
+                .split(',')
+                .filter(|pair| !pair.is_empty())
+                .all(|pair| {
+                    let chars: Vec<char> = pair.chars().collect();
+                    chars.len() == 3 && chars[1] == ':' && chars[0] != chars[2]
+                });
+            if valid {
+                Ok(())
+            } else {
+                Err(format!("Invalid argument: matchpairs={}", pairs))
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+// Changes a value with `+=`, `-=` or `^=`. Numbers are added, subtracted
+// or multiplied, strings appended, removed or prepended, and the same for
+// the items of a list. Returns the error without the argument.
+fn modify(kind: Kind, old: &Value, op: &str, arg: &str) -> Result<Value, &'static str> {
+    if let Value::Number(old) = *old {
+        let n: usize = arg.parse().map_err(|_| "Number required after =")?;
+        let new = match op {
+            "+=" => old.checked_add(n),
+            "-=" => Some(old.saturating_sub(n)),
+            "^=" => old.checked_mul(n),
+            _ => Some(n),
+        };
+        return new.map(Value::Number).ok_or("Invalid argument");
+    }
+
+    let old = match old {
+        Value::String(old) => old,
+        _ => unreachable!(),
+    };
+    let (sep, present) = match kind {
+        Kind::List => (
+            if old.is_empty() || arg.is_empty() {
+                ""
+            } else {
+                ","
+            },
+            old.split(',').any(|item| item == arg),
+        ),
+        _ => ("", false),
+    };
+
+    Ok(Value::String(match op {
+        "+=" if !present => format!("{}{}{}", old, sep, arg),
+        "^=" if !present => format!("{}{}{}", arg, sep, old),
+        "-=" if kind == Kind::List => old
+            .split(',')
+            .filter(|&item| item != arg)
+            .collect::<Vec<_>>()
+            .join(","),
+        "-=" => old.replacen(arg, "", 1),
+        "+=" | "^=" => old.clone(),
+        _ => arg.to_string(),
+    }))
+}
+
+// Values of the local options of a buffer, indexed like the definitions.
+pub struct LocalOptions(Vec<Value>);
+
+impl LocalOptions {
+    // Used to draw a buffer shown in a window other than the current one.
+    pub fn tabstop(&self) -> usize {
+        match self.0[find("tabstop").unwrap()] {
+            Value::Number(n) => n,
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub struct Options {
+    global: Vec<Value>,
+    // Those of the current buffer.
+    local: LocalOptions,
+    // Names of the options assigned since the last `take_assigned`.
+    assigned: Vec<&'static str>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let values: Vec<Value> = DEFINITIONS.iter().map(|def| (def.default)()).collect();
+        Self {
+            global: values.clone(),
+            local: LocalOptions(values),
+            assigned: vec![],
+        }
+    }
+}
+
+impl Options {
+    // Local options for a new buffer, taken from the global values.
+    pub fn new_local(&self) -> LocalOptions {
+        LocalOptions(self.global.clone())
+    }
+
+    // Makes the options of another buffer the current ones.
+    pub fn swap_local(&mut self, local: &mut LocalOptions) {
+        std::mem::swap(&mut self.local, local);
+    }
+
+    // Options assigned by `set`, even to the value they had, e.g. as
+    // `:set hlsearch` shows the matches again after `:nohlsearch`.
+    pub fn take_assigned(&mut self) -> Vec<&'static str> {
+        std::mem::take(&mut self.assigned)
+    }
+
+    // Value in effect, the local one for a local option.
+    fn get(&self, i: usize) -> &Value {
+        if DEFINITIONS[i].local {
+            &self.local.0[i]
+        } else {
+            &self.global[i]
+        }
+    }
+
+    fn value_in(&self, i: usize, scope: Scope) -> &Value {
+        match scope {
+            Scope::Global => &self.global[i],
+            _ => self.get(i),
+        }
+    }
+
+    fn assign(&mut self, i: usize, value: Value, scope: Scope) {
+        let def = &DEFINITIONS[i];
+        self.assigned.push(def.name);
+        if !def.local || scope != Scope::Local {
+            self.global[i] = value.clone();
+        }
+        if def.local && scope != Scope::Global {
+            self.local.0[i] = value;
+        }
+    }
+
+    fn show(&self, i: usize, scope: Scope) -> String {
+        let name = DEFINITIONS[i].name;
+        match self.value_in(i, scope) {
+            Value::Bool(true) => name.to_string(),
+            Value::Bool(false) => format!("no{}", name),
+            Value::Number(n) => format!("{}={}", name, n),
+            Value::String(s) => format!("{}={}", name, s),
+        }
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        match self.get(find(name).unwrap()) {
+            Value::Bool(b) => *b,
+            _ => unreachable!(),
+        }
+    }
+
+    fn number(&self, name: &str) -> usize {
+        match self.get(find(name).unwrap()) {
+            Value::Number(n) => *n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn string(&self, name: &str) -> &str {
+        match self.get(find(name).unwrap()) {
+            Value::String(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn ignorecase(&self) -> bool {
+        self.flag("ignorecase")
+    }
+
+    pub fn smartcase(&self) -> bool {
+        self.flag("smartcase")
+    }
+
+    pub fn hlsearch(&self) -> bool {
+        self.flag("hlsearch")
+    }
+
+    pub fn incsearch(&self) -> bool {
+        self.flag("incsearch")
+    }
+
+    pub fn wrapscan(&self) -> bool {
+        self.flag("wrapscan")
+    }
+
+    pub fn tabstop(&self) -> usize {
+        self.number("tabstop")
+    }
+
+    pub fn softtabstop(&self) -> usize {
+        self.number("softtabstop")
+    }
+
+    pub fn expandtab(&self) -> bool {
+        self.flag("expandtab")
+    }
+
+    pub fn exrc(&self) -> bool {
+        self.flag("exrc")
+    }
+
+    pub fn scroll(&self) -> usize {
+        self.number("scroll")
+    }
+
+    pub fn timeoutlen(&self) -> usize {
+        self.number("timeoutlen")
+    }
+
+    pub fn mapleader(&self) -> &str {
+        self.string("mapleader")
+    }
+
+    pub fn set_scroll(&mut self, lines: usize) {
+        self.assign(find("scroll").unwrap(), Value::Number(lines), Scope::Both);
+    }
+
+    // Columns of an indent step.
+    pub fn shift_width(&self) -> usize {
+        match self.number("shiftwidth") {
+            0 => self.tabstop(),
+            width => width,
+        }
+    }
+
+    pub fn matchpairs(&self) -> Vec<(char, char)> {
+        self.string("matchpairs")
+            .split(',')
+            .filter_map(|pair| {
+                let mut chars = pair.chars();
+                Some((chars.next()?, chars.nth(1)?))
+            })
+            .collect()
+    }
+
+    pub fn shortmess(&self, flag: char) -> bool {
+        self.string("shortmess").contains(flag)
+    }
+
+    // Whitespace filling the columns from `from` to `to`, with tabs unless
+    // `expandtab` is set.
+    pub fn whitespace(&self, from: usize, to: usize) -> String {
+        let tabstop = self.tabstop();
+        let mut s = String::new();
+        let mut column = from;
+        if !self.expandtab() {
+            while column < to {
+                let next = (column / tabstop + 1) * tabstop;
+                if next > to {
+                    break;
+                }
+                s.push('\t');
+                column = next;
+            }
+        }
+        s.push_str(&" ".repeat(to.saturating_sub(column)));
+        s
+    }
+
+    // Applies the arguments of `:set`, like `hls noincsearch is? ts=8`.
+    // Returns the values asked for. Without arguments these are the
+    // options changed from their default.
+    pub fn set(&mut self, arg: &str, scope: Scope) -> Result<String, String> {
+        let items = split_args(arg);
+        let mut shown = vec![];
+
+        if items.is_empty() {
+            for (i, def) in DEFINITIONS.iter().enumerate() {
+                if *self.value_in(i, scope) != (def.default)() {
+                    shown.push(self.show(i, scope));
+                }
+            }
+        }
+
+        for item in &items {
+            let name_len = item
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(item.len());
+            let (name, rest) = item.split_at(name_len);
+            let invalid = || format!("Invalid argument: {}", item);
+
+            if name == "all" {
+                match rest {
+                    "" => shown.extend((0..DEFINITIONS.len()).map(|i| self.show(i, scope))),
+                    "&" => {
+                        for (i, def) in DEFINITIONS.iter().enumerate() {
+                            self.assign(i, (def.default)(), scope);
+                        }
+                    }
+                    _ => return Err(invalid()),
+                }
+                continue;
+            }
+
+            // `noname` and `invname` turn a flag off or over.
+            let (i, prefix) = match find(name) {
+                Some(i) => (i, ""),
+                None => {
+                    let (prefix, i) = ["no", "inv"]
+                        .iter()
+                        .find_map(|prefix| Some((*prefix, find(name.strip_prefix(prefix)?)?)))
+                        .ok_or_else(|| format!("Unknown option: {}", name))?;
+                    if DEFINITIONS[i].kind != Kind::Bool || !rest.is_empty() {
+                        return Err(invalid());
+                    }
+                    (i, prefix)
+                }
+            };
+            let def = &DEFINITIONS[i];
+            let old = self.value_in(i, scope).clone();
+
+            let new = match (rest, def.kind) {
+                ("", Kind::Bool) => Value::Bool(match prefix {
+                    "no" => false,
+                    "inv" => old != Value::Bool(true),
+                    _ => true,
+                }),
+                ("", _) | ("?", _) => {
+                    shown.push(self.show(i, scope));
+                    continue;
+                }
+                ("!", Kind::Bool) => Value::Bool(old != Value::Bool(true)),
+                ("&", _) => (def.default)(),
+                (_, Kind::Bool) => return Err(invalid()),
+                (rest, kind) => {
+                    let op = ["+=", "-=", "^=", "=", ":"]
+                        .iter()
+                        .find(|op| rest.starts_with(*op))
+                        .ok_or_else(invalid)?;
+                    modify(kind, &old, op, &rest[op.len()..])
+                        .map_err(|message| format!("{}: {}", message, item))?
+                }
+            };
+
+            check(def.name, &new)?;
+            self.assign(i, new, scope);
+        }
+
+        Ok(shown.join("  "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(options: &Options, name: &str, scope: Scope) -> usize {
+        match options.value_in(find(name).unwrap(), scope) {
+            Value::Number(n) => *n,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        let mut options = Options::default();
+        options.set("ts=8", Scope::Both).unwrap();
+        assert_eq!(options.tabstop(), 8);
+        options.set("ts+=2", Scope::Both).unwrap();
+        assert_eq!(options.tabstop(), 10);
+        options.set("ts^=3 sw-=1", Scope::Both).unwrap();
+        assert_eq!((options.tabstop(), options.shift_width()), (30, 3));
+        options.set("sw-=10", Scope::Both).unwrap();
+        assert_eq!(options.shift_width(), 30);
+        options.set("ts:5 ts&", Scope::Both).unwrap();
+        assert_eq!(options.tabstop(), 4);
+        assert_eq!(
+            options.set("ts? sw", Scope::Both).unwrap(),
+            "tabstop=4  shiftwidth=0"
+        );
+
+        assert!(options.set("ts=x", Scope::Both).is_err());
+        assert!(options.set("ts-=8", Scope::Both).is_err());
+        assert!(options.set("ts=10000", Scope::Both).is_err());
+        assert!(options.set("ts!", Scope::Both).is_err());
+        assert_eq!(options.tabstop(), 4);
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let mut options = Options::default();
+        let max = usize::MAX.to_string();
+        options.set(&format!("tm={}", max), Scope::Both).unwrap();
+
+        assert_eq!(
+            options.set("tm+=1", Scope::Both),
+            Err("Invalid argument: tm+=1".to_string())
+        );
+        assert!(options.set("tm^=2", Scope::Both).is_err());
+        assert_eq!(options.timeoutlen(), usize::MAX);
+        options.set("tm^=1 tm-=1", Scope::Both).unwrap();
+        assert_eq!(options.timeoutlen(), usize::MAX - 1);
+    }
+
+    #[test]
+    fn flags() {
+        let mut options = Options::default();
+        options.set("ic", Scope::Both).unwrap();
+        assert!(options.ignorecase());
+        options.set("noic", Scope::Both).unwrap();
+        assert!(!options.ignorecase());
+        options.set("ic!", Scope::Both).unwrap();
+        assert!(options.ignorecase());
+        options.set("invignorecase", Scope::Both).unwrap();
+        assert!(!options.ignorecase());
+        options.set("hls! hlsearch?", Scope::Both).unwrap();
+        assert!(!options.hlsearch());
+        assert_eq!(options.set("hls?", Scope::Both).unwrap(), "nohlsearch");
+        options.set("hls&", Scope::Both).unwrap();
+        assert!(options.hlsearch());
+
+        assert!(options.set("ic=1", Scope::Both).is_err());
+        assert!(options.set("noic!", Scope::Both).is_err());
+        assert!(options.set("nots", Scope::Both).is_err());
+        assert_eq!(
+            options.set("nosuchoption", Scope::Both),
+            Err("Unknown option: nosuchoption".to_string())
+        );
+    }
+
+    #[test]
+    fn assigned_options() {
+        let mut options = Options::default();
+        options.set("hls ts? sw=2", Scope::Both).unwrap();
+        assert_eq!(options.take_assigned(), vec!["hlsearch", "shiftwidth"]);
+        assert!(options.take_assigned().is_empty());
+        assert!(options.set("ic ts=0", Scope::Both).is_err());
+        assert_eq!(options.take_assigned(), vec!["ignorecase"]);
+    }
+
+    #[test]
+    fn strings_and_lists() {
+        let mut options = Options::default();
+        options.set("mapleader=\\ ", Scope::Both).unwrap();
+        assert_eq!(options.mapleader(), " ");
+        options.set("shm+=I", Scope::Both).unwrap();
+        assert!(options.shortmess('I'));
+        options.set("shm-=I", Scope::Both).unwrap();
+        assert!(!options.shortmess('I'));
+
+        options.set("mps+=<:>", Scope::Both).unwrap();
+        options.set("mps+=<:>", Scope::Both).unwrap();
+        assert_eq!(options.string("matchpairs"), "(:),{:},[:],<:>");
+        options.set("mps-={:} mps^=«:»", Scope::Both).unwrap();
+        assert_eq!(options.string("matchpairs"), "«:»,(:),[:],<:>");
+        assert_eq!(options.matchpairs()[0], ('«', '»'));
+        options.set("mps=", Scope::Both).unwrap();
+        assert!(options.matchpairs().is_empty());
+
+        assert!(options.set("mps=ab", Scope::Both).is_err());
+        assert!(options.set("mps=a:a", Scope::Both).is_err());
+    }
+
+    #[test]
+    fn scopes() {
+        let mut options = Options::default();
+        options.set("ts=2", Scope::Local).unwrap();
+        assert_eq!(number(&options, "ts", Scope::Local), 2);
+        assert_eq!(number(&options, "ts", Scope::Global), 4);
+
+        options.set("sw=8", Scope::Global).unwrap();
+        assert_eq!(number(&options, "sw", Scope::Local), 4);
+        assert_eq!(number(&options, "sw", Scope::Global), 8);
+        assert_eq!(options.set("sw?", Scope::Global).unwrap(), "shiftwidth=8");
+
+        // A new buffer starts from the global values.
+        let local = options.new_local();
+        assert_eq!((local.tabstop(), options.tabstop()), (4, 2));
+
+        options.set("ts=6", Scope::Both).unwrap();
+        assert_eq!(number(&options, "ts", Scope::Local), 6);
+        assert_eq!(number(&options, "ts", Scope::Global), 6);
+
+        // Global options have a single value.
+        options.set("tm=50", Scope::Local).unwrap();
+        assert_eq!(number(&options, "tm", Scope::Global), 50);
+    }
+
+    #[test]
+    fn all() {
+        let mut options = Options::default();
+        assert_eq!(options.set("", Scope::Both).unwrap(), "");
+        options.set("ic ts=2 sw=3", Scope::Both).unwrap();
+        assert_eq!(
+            options.set("", Scope::Both).unwrap(),
+            "ignorecase  tabstop=2  shiftwidth=3"
+        );
+        assert_eq!(
+            options.set("all", Scope::Both).unwrap().split("  ").count(),
+            DEFINITIONS.len()
+        );
+
+        options.set("all&", Scope::Both).unwrap();
+        assert!(!options.ignorecase());
+        assert_eq!((options.tabstop(), options.shift_width()), (4, 4));
+        assert!(options.set("all!", Scope::Both).is_err());
+    }
+}
@@ -0,0 +1,979 @@
+// Regular expressions with the syntax of Vim. The pattern is compiled to a
+// program run by a Pike VM, which follows all alternatives at once and so
+// never backtracks.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Limits on what a pattern may ask for, as it is compiled while it is
+// typed with `incsearch`: the count in `\{n,m}`, the nesting of groups and
+// the number of nodes compiled once repetitions are unrolled.
+const MAX_COUNT: usize = 1000;
+const MAX_NESTING: usize = 100;
+const MAX_COMPILED: usize = 100_000;
+
+// How many characters have a special meaning without a backslash, set by
+// `\v`, `\m`, `\M` and `\V` in the pattern.
+#[derive(Clone, Copy, PartialEq)]
+enum MagicLevel {
+    VeryMagic,
+    Magic,
+    NoMagic,
+    VeryNoMagic,
+}
+
+#[derive(Clone, Copy)]
+enum NamedClass {
+    Space,
+    Digit,
+    Word,
+    Alpha,
+    Lower,
+    Upper,
+    Hex,
+    Head,
+    Alnum,
+    Punct,
+}
+
+impl NamedClass {
+    fn from_escape(c: char) -> Option<Self> {
+        let class = match c.to_ascii_lowercase() {
+            's' => NamedClass::Space,
+            'd' => NamedClass::Digit,
+            'w' => NamedClass::Word,
+            'a' => NamedClass::Alpha,
+            'l' => NamedClass::Lower,
+            'u' => NamedClass::Upper,
+            'x' => NamedClass::Hex,
+            'h' => NamedClass::Head,
+            _ => return None,
+        };
+        Some(class)
+    }
+
+    fn from_posix(name: &str) -> Option<Self> {
+        let class = match name {
+            "space" | "blank" => NamedClass::Space,
+            "digit" => NamedClass::Digit,
+            "alpha" => NamedClass::Alpha,
+            "lower" => NamedClass::Lower,
+            "upper" => NamedClass::Upper,
+            "xdigit" => NamedClass::Hex,
+            "alnum" => NamedClass::Alnum,
+            "punct" => NamedClass::Punct,
+            _ => return None,
+        };
+        Some(class)
+    }
+
+    fn contains(&self, c: char) -> bool {
+        match self {
+            NamedClass::Space => c == ' ' || c == '\t',
+            NamedClass::Digit => c.is_ascii_digit(),
+            NamedClass::Word => c.is_ascii_alphanumeric() || c == '_',
+            NamedClass::Alpha => c.is_ascii_alphabetic(),
+            NamedClass::Lower => c.is_ascii_lowercase(),
+            NamedClass::Upper => c.is_ascii_uppercase(),
+            NamedClass::Hex => c.is_ascii_hexdigit(),
+            NamedClass::Head => c.is_ascii_alphabetic() || c == '_',
+            NamedClass::Alnum => c.is_ascii_alphanumeric(),
+            NamedClass::Punct => c.is_ascii_punctuation(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ClassItem {
+    Range(char, char),
+    Named(NamedClass),
+}
+
+#[derive(Clone)]
+struct Class {
+    items: Vec<ClassItem>,
+    negated: bool,
+}
+
+impl Class {
+    fn contains(&self, c: char, ignore_case: bool) -> bool {
+        let contains = |c: char| {
+            self.items.iter().any(|item| match *item {
+                ClassItem::Range(from, to) => from <= c && c <= to,
+                ClassItem::Named(class) => class.contains(c),
+            })
+        };
+
+        let found = contains(c)
+            || (ignore_case && (c.to_lowercase().any(contains) || c.to_uppercase().any(contains)));
+        found != self.negated
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordStart,
+    WordEnd,
+}
+
+enum Token {
+    Literal(char),
+    Alt,
+    Open,
+    OpenNonCapturing,
+    Close,
+    Star,
+    Plus,
+    Question,
+    Brace,
+    Any,
+    ClassOpen,
+    Named(NamedClass, bool),
+    Assert(Assertion),
+}
+
+impl Token {
+    // Character the token stands for when it is not special where it
+    // appears, e.g. `*` at the start of the pattern.
+    fn as_literal(&self) -> Option<char> {
+        let c = match self {
+            Token::Literal(c) => *c,
+            Token::Star => '*',
+            Token::Plus => '+',
+            Token::Question => '?',
+            Token::Brace => '{',
+            Token::Assert(Assertion::LineStart) => '^',
+            Token::Assert(Assertion::LineEnd) => '$',
+            _ => return None,
+        };
+        Some(c)
+    }
+}
+
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    magic: MagicLevel,
+    peeked: Option<Option<Token>>,
+    num_groups: usize,
+    // Groups open around the part being parsed.
+    depth: usize,
+    // Set by `\c` and `\C` anywhere in the pattern.
+    ignore_case: Option<bool>,
+}
+
+impl<'a> Parser<'a> {
+    fn operator(c: char) -> Option<Token> {
+        let token = match c {
+            '|' => Token::Alt,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '*' => Token::Star,
+            '+' => Token::Plus,
+            '?' | '=' => Token::Question,
+            '{' => Token::Brace,
+            '.' => Token::Any,
+            '[' => Token::ClassOpen,
+            '<' => Token::Assert(Assertion::WordStart),
+            '>' => Token::Assert(Assertion::WordEnd),
+            '^' => Token::Assert(Assertion::LineStart),
+            '$' => Token::Assert(Assertion::LineEnd),
+            _ => return None,
+        };
+        Some(token)
+    }
+
+    // Whether the operator character needs a backslash to be special.
+    fn needs_backslash(&self, c: char) -> bool {
+        match c {
+            '^' | '$' => false,
+            '*' | '.' | '[' => matches!(self.magic, MagicLevel::NoMagic | MagicLevel::VeryNoMagic),
+            _ => self.magic != MagicLevel::VeryMagic,
+        }
+    }
+
+    fn read_token(&mut self) -> Option<Token> {
+        let c = self.chars.next()?;
+
+        if c == '%' && self.magic == MagicLevel::VeryMagic && self.chars.peek() == Some(&'(') {
+            self.chars.next();
+            return Some(Token::OpenNonCapturing);
+        }
+        if c != '\\' {
+            return match Self::operator(c) {
+                Some(token) if !self.needs_backslash(c) => Some(token),
+                _ => Some(Token::Literal(c)),
+            };
+        }
+
+        let c = match self.chars.next() {
+            Some(c) => c,
+            None => return Some(Token::Literal('\\')),
+        };
+        let magic = match c {
+            'v' => Some(MagicLevel::VeryMagic),
+            'm' => Some(MagicLevel::Magic),
+            'M' => Some(MagicLevel::NoMagic),
+            'V' => Some(MagicLevel::VeryNoMagic),
+            _ => None,
+        };
+        if let Some(magic) = magic {
+            self.magic = magic;
+            return self.read_token();
+        }
+        if c == 'c' || c == 'C' {
+            self.ignore_case = Some(c == 'c');
+            return self.read_token();
+        }
+
+        if let Some(class) = NamedClass::from_escape(c) {
+            return Some(Token::Named(class, c.is_ascii_uppercase()));
+        }
+        let token = match c {
+            't' => Token::Literal('\t'),
+            'e' => Token::Literal('\x1B'),
+            'n' => Token::Literal('\n'),
+            '%' if self.chars.peek() == Some(&'(') => {
+                self.chars.next();
+                Token::OpenNonCapturing
+            }
+            c => match Self::operator(c) {
+                Some(token) if self.needs_backslash(c) => token,
+                _ => Token::Literal(c),
+            },
+        };
+        Some(token)
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.read_token());
+        }
+        self.peeked.as_ref().unwrap().as_ref()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        match self.peeked.take() {
+            Some(token) => token,
+            None => self.read_token(),
+        }
+    }
+
+    fn parse_alt(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while let Some(Token::Alt) = self.peek() {
+            self.next();
+            branches.push(self.parse_concat()?);
+        }
+
+        if branches.len() == 1 {
+            Ok(branches.pop().unwrap())
+        } else {
+            Ok(Node::Alt(branches))
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes: Vec<Node> = vec![];
+
+        loop {
+            let token = match self.peek() {
+                None | Some(Token::Alt) | Some(Token::Close) => break,
+                _ => self.next().unwrap(),
+            };
+
+            let node = match token {
+                Token::Literal(c) => Node::Char(c),
+                Token::Any => Node::Any,
+                Token::Named(class, negated) => Node::Class(Class {
+                    items: vec![ClassItem::Named(class)],
+                    negated,
+                }),
+                Token::ClassOpen => match self.parse_class() {
+                    Some(class) => Node::Class(class),
+                    None => Node::Char('['),
+                },
+                Token::Open | Token::OpenNonCapturing => {
+                    let index = match token {
+                        Token::Open => {
+                            self.num_groups += 1;
+                            Some(self.num_groups)
+                        }
+                        _ => None,
+                    };
+                    if self.depth == MAX_NESTING {
+                        return Err("Too many nested groups".to_string());
+                    }
+                    self.depth += 1;
+                    let node = self.parse_alt()?;
+                    self.depth -= 1;
+                    match self.next() {
+                        Some(Token::Close) => Node::Group(Box::new(node), index),
+                        _ => return Err("Unmatched \\(".to_string()),
+                    }
+                }
+                // `^` is only special at the start of a branch and `$` at its end.
+                Token::Assert(Assertion::LineStart) if !nodes.is_empty() => Node::Char('^'),
+                Token::Assert(Assertion::LineEnd)
+                    if !matches!(self.peek(), None | Some(Token::Alt) | Some(Token::Close)) =>
+                {
+                    Node::Char('$')
+                }
+                Token::Assert(assertion) => Node::Assert(assertion),
+                ref token => match (nodes.pop(), token) {
+                    // As in Vim, `a**` is an error rather than a repetition
+                    // of a repetition.
+                    (Some(Node::Repeat { .. }), _) => {
+                        return Err("Nested multi".to_string());
+                    }
+                    (Some(node), Token::Brace) => self.parse_brace(node)?,
+                    (Some(node), _) => {
+                        let (min, max) = match token {
+                            Token::Star => (0, None),
+                            Token::Plus => (1, None),
+                            _ => (0, Some(1)),
+                        };
+                        Node::Repeat {
+                            node: Box::new(node),
+                            min,
+                            max,
+                            greedy: true,
+                        }
+                    }
+                    (None, token) => Node::Char(token.as_literal().unwrap()),
+                },
+            };
+            nodes.push(node);
+        }
+
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    // Parses `{n,m}` after the opening brace. A leading `-` makes the
+    // repetition match as few times as possible.
+    fn parse_brace(&mut self, node: Node) -> Result<Node, String> {
+        let mut content = String::new();
+        loop {
+            match self.chars.next() {
+                Some('}') => break,
+                Some('\\') if self.chars.peek() == Some(&'}') => {}
+                Some(c) => content.push(c),
+                None => return Err("Missing }".to_string()),
+            }
+        }
+
+        let greedy = !content.starts_with('-');
+        let content = content.trim_start_matches('-');
+        let error = || format!("Syntax error in {{{}}}", content);
+        let number = |s: &str| -> Result<Option<usize>, String> {
+            if s.is_empty() {
+                Ok(None)
+            } else {
+                s.parse().map(Some).map_err(|_| error())
+            }
+        };
+
+        let (min, max) = match content.split_once(',') {
+            Some((min, max)) => (number(min)?.unwrap_or(0), number(max)?),
+            None => match number(content)? {
+                Some(n) => (n, Some(n)),
+                None => (0, None),
+            },
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(error());
+        }
+        if min.max(max.unwrap_or(0)) > MAX_COUNT {
+            return Err(format!("Count too large in {{{}}}", content));
+        }
+
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    // Parses a collection like `[^a-z_]` after the opening bracket. Returns
+    // None if it is not closed, then the bracket is matched literally.
+    fn parse_class(&mut self) -> Option<Class> {
+        let saved = self.chars.clone();
+        let mut items = vec![];
+
+        let negated = self.chars.peek() == Some(&'^');
+        if negated {
+            self.chars.next();
+        }
+        let mut first = true;
+
+        loop {
+            let c = match self.chars.next() {
+                Some(']') if !first => break,
+                Some('[') if self.chars.peek() == Some(&':') => {
+                    let rest: String = self.chars.clone().collect();
+                    match rest[1..].split_once(":]") {
+                        Some((name, _)) if NamedClass::from_posix(name).is_some() => {
+                            for _ in 0..name.len() + 3 {
+                                self.chars.next();
+                            }
+                            items.push(ClassItem::Named(NamedClass::from_posix(name).unwrap()));
+                            first = false;
+                            continue;
+                        }
+                        _ => '[',
+                    }
+                }
+                Some('\\') => match self.chars.next() {
+                    Some('t') => '\t',
+                    Some('e') => '\x1B',
+                    Some('n') => '\n',
+                    Some(c @ ('\\' | ']' | '^' | '-')) => c,
+                    Some(c) => {
+                        items.push(ClassItem::Range('\\', '\\'));
+                        c
+                    }
+                    None => '\\',
+                },
+                Some(c) => c,
+                None => {
+                    self.chars = saved;
+                    return None;
+                }
+            };
+            first = false;
+
+            let mut lookahead = self.chars.clone();
+            if lookahead.next() == Some('-') && lookahead.peek().is_some_and(|&c| c != ']') {
+                self.chars.next();
+                let to = self.chars.next().unwrap();
+                items.push(ClassItem::Range(c, to));
+            } else {
+                items.push(ClassItem::Range(c, c));
+            }
+        }
+
+        Some(Class { items, negated })
+    }
+}
+
+#[derive(Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    // Follows both instructions, the first one having priority.
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+    // Nodes compiled so far, counting every copy of a repeated one.
+    compiled: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> usize {
+        self.program.push(inst);
+        self.program.len() - 1
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        self.compiled += 1;
+        if self.compiled > MAX_COMPILED {
+            return Err("Pattern too long".to_string());
+        }
+
+        match node {
+            Node::Empty => {}
+            Node::Char(c) => {
+                self.emit(Inst::Char(*c));
+            }
+            Node::Any => {
+                self.emit(Inst::Any);
+            }
+            Node::Class(class) => {
+                self.emit(Inst::Class(class.clone()));
+            }
+            Node::Assert(assertion) => {
+                self.emit(Inst::Assert(*assertion));
+            }
+            Node::Group(node, index) => {
+                if let Some(i) = index {
+                    self.emit(Inst::Save(2 * i));
+                }
+                self.compile(node)?;
+                if let Some(i) = index {
+                    self.emit(Inst::Save(2 * i + 1));
+                }
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alt(branches) => {
+                let mut jumps = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.compile(branch)?;
+                        jumps.push(self.emit(Inst::Jmp(0)));
+                        self.program[split] = Inst::Split(split + 1, self.program.len());
+                    } else {
+                        self.compile(branch)?;
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+
+                let split = |from: usize, to: usize| {
+                    if *greedy {
+                        Inst::Split(from, to)
+                    } else {
+                        Inst::Split(to, from)
+                    }
+                };
+
+                match max {
+                    None => {
+                        let start = self.emit(Inst::Split(0, 0));
+                        self.compile(node)?;
+                        self.emit(Inst::Jmp(start));
+                        self.program[start] = split(start + 1, self.program.len());
+                    }
+                    Some(max) => {
+                        let mut splits = vec![];
+                        for _ in *min..*max {
+                            splits.push(self.emit(Inst::Split(0, 0)));
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for s in splits {
+                            self.program[s] = split(s + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Regex {
+    program: Vec<Inst>,
+    num_groups: usize,
+    ignore_case: bool,
+}
+
+struct Thread {
+    pc: usize,
+    slots: Vec<Option<usize>>,
+}
+
+// Threads of the VM at one position of the text, in order of priority.
+struct ThreadList {
+    threads: Vec<Thread>,
+    visited: Vec<bool>,
+}
+
+impl ThreadList {
+    fn new(len: usize) -> Self {
+        Self {
+            threads: vec![],
+            visited: vec![false; len],
+        }
+    }
+}
+
+// Whether the pattern has an uppercase letter, not counting the ones after a
+// backslash like in `\S`.
+pub fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+// Characters of the words delimited by `\<` and `\>`, letters outside
+// ASCII included.
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Regex {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().peekable(),
+            magic: MagicLevel::Magic,
+            peeked: None,
+            num_groups: 0,
+            depth: 0,
+            ignore_case: None,
+        };
+
+        let node = parser.parse_alt()?;
+        if parser.next().is_some() {
+            return Err("Unmatched \\)".to_string());
+        }
+
+        let mut compiler = Compiler {
+            program: vec![],
+            compiled: 0,
+        };
+        compiler.emit(Inst::Save(0));
+        compiler.compile(&node)?;
+        compiler.emit(Inst::Save(1));
+        compiler.emit(Inst::Match);
+
+        Ok(Self {
+            program: compiler.program,
+            num_groups: parser.num_groups,
+            ignore_case: parser.ignore_case.unwrap_or(ignore_case),
+        })
+    }
+
+    fn char_matches(&self, expected: char, c: char) -> bool {
+        expected == c || (self.ignore_case && expected.to_lowercase().eq(c.to_lowercase()))
+    }
+
+    fn assertion_holds(assertion: Assertion, text: &str, pos: usize) -> bool {
+        let prev = text[..pos].chars().next_back().is_some_and(is_word_char);
+        let next = text[pos..].chars().next().is_some_and(is_word_char);
+        match assertion {
+            Assertion::LineStart => pos == 0,
+            Assertion::LineEnd => pos == text.len(),
+            Assertion::WordStart => !prev && next,
+            Assertion::WordEnd => prev && !next,
+        }
+    }
+
+    // Adds a thread, following the instructions that do not consume a
+    // character. The alternatives are kept on a stack rather than followed
+    // by recursion, as a pattern like `\(\)\{1000}` chains many of them.
+    fn add_thread(
+        &self,
+        list: &mut ThreadList,
+        pc: usize,
+        slots: Vec<Option<usize>>,
+        text: &str,
+        pos: usize,
+    ) {
+        let mut stack = vec![(pc, slots)];
+
+        while let Some((pc, mut slots)) = stack.pop() {
+            if list.visited[pc] {
+                continue;
+            }
+            list.visited[pc] = true;
+
+            match self.program[pc] {
+                Inst::Jmp(to) => stack.push((to, slots)),
+                Inst::Split(first, second) => {
+                    // The first one is followed before the second.
+                    stack.push((second, slots.clone()));
+                    stack.push((first, slots));
+                }
+                Inst::Save(slot) => {
+                    slots[slot] = Some(pos);
+                    stack.push((pc + 1, slots));
+                }
+                Inst::Assert(assertion) => {
+                    if Self::assertion_holds(assertion, text, pos) {
+                        stack.push((pc + 1, slots));
+                    }
+                }
+                _ => list.threads.push(Thread { pc, slots }),
+            }
+        }
+    }
+
+    // Leftmost match starting at the byte `from` or after it. The text before
+    // `from` is still seen by `^` and word boundaries. Returns the bytes of
+    // the match followed by those of every group.
+    pub fn find_at(&self, text: &str, from: usize) -> Option<Vec<Option<(usize, usize)>>> {
+        let num_slots = 2 * (self.num_groups + 1);
+        let mut current = ThreadList::new(self.program.len());
+        let mut matched: Option<Vec<Option<usize>>> = None;
+        let mut pos = from;
+
+        loop {
+            // A new attempt starts at every position until a match is found.
+            if matched.is_none() {
+                self.add_thread(&mut current, 0, vec![None; num_slots], text, pos);
+            }
+            if current.threads.is_empty() && matched.is_some() {
+                break;
+            }
+
+            let c = text[pos..].chars().next();
+            let next_pos = pos + c.map_or(0, char::len_utf8);
+            let mut next = ThreadList::new(self.program.len());
+
+            for thread in current.threads {
+                let consumed = match (&self.program[thread.pc], c) {
+                    (Inst::Match, _) => {
+                        // Threads with a lower priority are dropped.
+                        matched = Some(thread.slots);
+                        break;
+                    }
+                    (Inst::Char(expected), Some(c)) => self.char_matches(*expected, c),
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some(c)) => class.contains(c, self.ignore_case),
+                    _ => false,
+                };
+                if consumed {
+                    self.add_thread(&mut next, thread.pc + 1, thread.slots, text, next_pos);
+                }
+            }
+
+            if c.is_none() {
+                break;
+            }
+            pos = next_pos;
+            current = next;
+        }
+
+        let slots = matched?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|pair| match (pair[0], pair[1]) {
+                    (Some(start), Some(end)) => Some((start, end)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::searching::line_matches;
+
+    // Text of the leftmost match of the pattern.
+    fn find<'a>(pattern: &str, text: &'a str) -> Option<&'a str> {
+        let regex = Regex::new(pattern, false).unwrap();
+        let (start, end) = regex.find_at(text, 0)?[0]?;
+        Some(&text[start..end])
+    }
+
+    // Texts of the groups of the leftmost match, the whole match first.
+    fn groups<'a>(pattern: &str, text: &'a str) -> Vec<Option<&'a str>> {
+        let regex = Regex::new(pattern, false).unwrap();
+        let spans = regex.find_at(text, 0).unwrap();
+        spans
+            .into_iter()
+            .map(|span| span.map(|(start, end)| &text[start..end]))
+            .collect()
+    }
+
+    #[test]
+    fn magic_levels() {
+        // Magic, the default: `*` is special, `+` and `(` need a backslash.
+        assert_eq!(find("ba*", "xbaaa"), Some("baaa"));
+        assert_eq!(find("a+", "aa a+"), Some("a+"));
+        assert_eq!(find("a\\+", "baa"), Some("aa"));
+
+        assert_eq!(find("\\v(a|b)+", "xabbac"), Some("abba"));
+        assert_eq!(find("\\va{2}", "aaa"), Some("aa"));
+        assert_eq!(find("\\v\\+", "a+"), Some("+"));
+
+        assert_eq!(find("\\Ma*", "aaa a*"), Some("a*"));
+        assert_eq!(find("\\Ma\\*", "baa"), Some(""));
+        assert_eq!(find("\\M.", "a."), Some("."));
+
+        assert_eq!(find("\\Va.b", "axb a.b"), Some("a.b"));
+        assert_eq!(find("\\Va\\.b", "axb"), Some("axb"));
+        assert_eq!(find("\\V^a", "ba"), None);
+
+        // The level may change within the pattern.
+        assert_eq!(find("\\Va*\\ma*", "a*aa"), Some("a*aa"));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(find("[a-c]\\+", "xxabcd"), Some("abc"));
+        assert_eq!(find("[^a-c]\\+", "abxyc"), Some("xy"));
+        assert_eq!(find("[]a]\\+", "x]a]y"), Some("]a]"));
+        assert_eq!(find("[a\\-]\\+", "b-a-"), Some("-a-"));
+        assert_eq!(find("[[:digit:]x]\\+", "a1x2b"), Some("1x2"));
+        // An unclosed bracket is matched literally.
+        assert_eq!(find("[ab", "a[ab"), Some("[ab"));
+
+        assert_eq!(find("\\d\\+", "ab123c"), Some("123"));
+        assert_eq!(find("\\w\\+", "  foo_1 "), Some("foo_1"));
+        assert_eq!(find("\\s\\+", "a \tb"), Some(" \t"));
+        assert_eq!(find("\\x\\+", "xyzBEEF"), Some("BEEF"));
+        assert_eq!(find("\\h\\w*", "1_a1"), Some("_a1"));
+
+        // Uppercase names negate the class.
+        assert_eq!(find("\\D\\+", "12ab3"), Some("ab"));
+        assert_eq!(find("\\S\\+", "  foo bar"), Some("foo"));
+        assert_eq!(find("\\W\\+", "ab, cd"), Some(", "));
+        assert_eq!(find("\\A\\+", "ab12cd"), Some("12"));
+        assert_eq!(find("\\L\\+", "abCDe"), Some("CD"));
+        assert_eq!(find("\\U\\+", "ABcdE"), Some("cd"));
+    }
+
+    #[test]
+    fn groups_and_alternation() {
+        assert_eq!(
+            groups("\\(foo\\|bar\\)\\(baz\\)\\?", "xbarbaz"),
+            [Some("barbaz"), Some("bar"), Some("baz")]
+        );
+        assert_eq!(
+            groups("\\(a\\)\\|\\(b\\)", "b"),
+            [Some("b"), None, Some("b")]
+        );
+        assert_eq!(groups("\\%(a\\)\\(b\\)", "ab"), [Some("ab"), Some("b")]);
+
+        // The first alternative matching at the leftmost position wins.
+        assert_eq!(find("ab\\|abc", "abc"), Some("ab"));
+        assert_eq!(find("c\\|abc", "abc"), Some("abc"));
+        assert_eq!(find("a\\|", "b"), Some(""));
+
+        assert!(Regex::new("\\(a", false).is_err());
+        assert!(Regex::new("a\\)", false).is_err());
+    }
+
+    #[test]
+    fn counted_repetition() {
+        assert_eq!(find("a\\{2,3}", "aaaa"), Some("aaa"));
+        assert_eq!(find("a\\{2}", "aaaa"), Some("aa"));
+        assert_eq!(find("ba\\{,2}", "baaa"), Some("baa"));
+        assert_eq!(find("ba\\{2,}", "baaaa"), Some("baaaa"));
+        assert_eq!(find("ba\\{}", "baaa"), Some("baaa"));
+        assert_eq!(find("ba\\{2,3}", "ba"), None);
+
+        // A leading `-` matches as few as possible.
+        assert_eq!(find("a\\{-1,3}", "aaa"), Some("a"));
+        assert_eq!(find("a\\{-}", "aaa"), Some(""));
+        assert_eq!(find("x\\(a\\{-}\\)y", "xaay"), Some("xaay"));
+
+        assert!(Regex::new("a\\{3,1}", false).is_err());
+        assert!(Regex::new("a\\{x}", false).is_err());
+        assert!(Regex::new("a\\{2", false).is_err());
+    }
+
+    #[test]
+    fn large_patterns_are_rejected() {
+        assert!(Regex::new("a\\{100000}", false).is_err());
+        assert!(Regex::new("a\\{2,100000}", false).is_err());
+        assert!(Regex::new("a*\\{1000}", false).is_err());
+        assert!(Regex::new("\\(a\\{1000}\\)\\{1000}", false).is_err());
+        assert!(Regex::new("\\%(\\%(\\)\\{1000}\\)\\{1000}", false).is_err());
+
+        let nested = "\\(".repeat(100_000) + &"\\)".repeat(100_000);
+        assert!(Regex::new(&nested, false).is_err());
+    }
+
+    #[test]
+    fn long_chains_of_empty_groups() {
+        // Every group is followed without recursion when adding threads.
+        assert_eq!(find("\\(\\)\\{1000}b", "ab"), Some("b"));
+
+        let nested = "\\(".repeat(100) + "b" + &"\\)".repeat(100);
+        assert_eq!(groups(&nested, "ab")[100], Some("b"));
+        let nested = "\\(".repeat(100) + &"\\)".repeat(100) + "b";
+        assert_eq!(find(&nested, "ab"), Some("b"));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let regex = Regex::new("\\<is\\>", false).unwrap();
+        assert_eq!(regex.find_at("this is", 0).unwrap()[0], Some((5, 7)));
+        assert_eq!(find("\\<is\\>", "this isn't"), None);
+        assert_eq!(find("\\<\\w", " _a"), Some("_"));
+
+        // Letters outside ASCII are part of words.
+        assert_eq!(find("\\<za\\>", "zażółć"), None);
+        assert_eq!(find("\\<ł", "żółw"), None);
+        assert_eq!(find("ł\\>", "żół"), Some("ł"));
+
+        // The text before the start of the search is seen.
+        let regex = Regex::new("\\<b", false).unwrap();
+        assert_eq!(regex.find_at("ab b", 1), Some(vec![Some((3, 4))]));
+    }
+
+    #[test]
+    fn ignore_case() {
+        let regex = Regex::new("foo", true).unwrap();
+        assert_eq!(regex.find_at("a FoO", 0), Some(vec![Some((2, 5))]));
+
+        assert_eq!(find("\\cFOO", "a foo"), Some("foo"));
+        assert_eq!(find("fo\\cO", "FOO"), Some("FOO"));
+        assert_eq!(find("\\c[a-c]\\+", "xABC"), Some("ABC"));
+        assert_eq!(find("\\c\\l\\+", "1Ab"), Some("Ab"));
+        assert_eq!(find("\\cżółw", "ŻÓŁW"), Some("ŻÓŁW"));
+
+        let regex = Regex::new("\\Cfoo", true).unwrap();
+        assert_eq!(regex.find_at("FOO foo", 0), Some(vec![Some((4, 7))]));
+
+        assert!(has_uppercase("fooBar"));
+        assert!(!has_uppercase("foo\\Sbar"));
+    }
+
+    #[test]
+    fn empty_matches_advance() {
+        let spans = |pattern: &str, line: &str| -> Vec<(usize, usize)> {
+            let regex = Regex::new(pattern, false).unwrap();
+            line_matches(line, &regex)
+                .iter()
+                .map(|m| (m.start, m.end))
+                .collect()
+        };
+
+        // An empty match moves on by a whole character.
+        assert_eq!(spans("x*", "ażb"), [(0, 0), (1, 1), (3, 3), (4, 4)]);
+        assert_eq!(spans("x*", "axxb")[..2], [(0, 0), (1, 3)]);
+        assert_eq!(spans("^", ""), [(0, 0)]);
+        assert_eq!(spans("$", "ab"), [(2, 2)]);
+        assert_eq!(spans("a", "aa"), [(0, 1), (1, 2)]);
+        assert_eq!(spans("b", "aa"), []);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RegisterKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+// Content of a register. Linewise text holds whole lines separated by
+// '\n', without the final newline, blockwise text the parts of the lines
+// in the block.
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+
+impl Register {
+    fn append(&mut self, other: &Register) {
+        if self.kind == RegisterKind::Linewise || other.kind == RegisterKind::Linewise {
+            self.text.push('\n');
+            self.kind = RegisterKind::Linewise;
+        } else if self.kind == RegisterKind::Blockwise || other.kind == RegisterKind::Blockwise {
+            self.text.push('\n');
+            self.kind = RegisterKind::Blockwise;
+        }
+        self.text.push_str(&other.text);
+    }
+}
+
+// Registers written by yanks and deletes. The read-only registers `%` and
+// `/` are provided by the editor, see Editor::get_register.
+pub struct Registers {
+    unnamed: Option<Register>,
+    named: HashMap<char, Register>,
+    // "0 holds the last yank, "1 to "9 the history of deletes.
+    numbered: [Option<Register>; 10],
+    small_delete: Option<Register>,
+}
+
+pub fn is_valid_name(name: char) -> bool {
+    name.is_ascii_alphanumeric() || ['"', '-', '_', '%', '/'].contains(&name)
+}
+
+// The numbered registers are only filled by yanks and deletes into the
+// unnamed one, the others are provided by the editor.
+fn check_writable(name: Option<char>) -> Result<(), String> {
+    match name {
+        Some(name @ ('0'..='9' | '%' | '/' | ':')) => {
+            Err(format!("Invalid register name: '{}'", name))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Self {
+            unnamed: None,
+            named: HashMap::new(),
+            numbered: Default::default(),
+            small_delete: None,
+        }
+    }
+
+    pub fn get(&self, name: char) -> Option<Register> {
+        match name {
+            '"' => self.unnamed.clone(),
+            'a'..='z' => self.named.get(&name).cloned(),
+            'A'..='Z' => self.named.get(&name.to_ascii_lowercase()).cloned(),
+            '0'..='9' => self.numbered[name.to_digit(10).unwrap() as usize].clone(),
+            '-' => self.small_delete.clone(),
+            _ => None,
+        }
+    }
+
+    // Writes to a named register. An uppercase name appends to the register
+    // with the lowercase one.
+    fn set_named(&mut self, name: char, register: &Register) {
+        if name.is_ascii_uppercase() {
+            let lower = name.to_ascii_lowercase();
+            match self.named.get_mut(&lower) {
+                Some(existing) => existing.append(register),
+                None => {
+                    self.named.insert(lower, register.clone());
+                }
+            }
+            self.unnamed = self.named.get(&lower).cloned();
+        } else {
+            self.named.insert(name, register.clone());
+            self.unnamed = Some(register.clone());
+        }
+    }
+
+    // Stores a recorded macro. It does not change the unnamed register.
+    pub fn record(&mut self, name: char, text: String) {
+        let register = Register {
+            text,
+            kind: RegisterKind::Charwise,
+        };
+
+        if name.is_ascii_uppercase() {
+            let lower = name.to_ascii_lowercase();
+            match self.named.get_mut(&lower) {
+                Some(existing) => existing.append(&register),
+                None => {
+                    self.named.insert(lower, register);
+                }
+            }
+        } else {
+            self.named.insert(name, register);
+        }
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) -> Result<(), String> {
+        check_writable(name)?;
+        match name {
+            Some('_') => {}
+            Some(name) if name.is_ascii_alphabetic() => self.set_named(name, &register),
+            _ => {
+                self.numbered[0] = Some(register.clone());
+                self.unnamed = Some(register);
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes within a line go to the small delete register, bigger ones
+    // shift the numbered registers.
+    pub fn delete(&mut self, name: Option<char>, register: Register) -> Result<(), String> {
+        check_writable(name)?;
+        match name {
+            Some('_') => {}
+            Some(name) if name.is_ascii_alphabetic() => self.set_named(name, &register),
+            _ => {
+                if register.kind == RegisterKind::Linewise || register.text.contains('\n') {
+                    self.numbered[1..].rotate_right(1);
+                    self.numbered[1] = Some(register.clone());
+                } else {
+                    self.small_delete = Some(register.clone());
+                }
+                self.unnamed = Some(register);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use super::motions::Motion;
+use super::operators::Operator;
+use crate::terminal::input::Key;
+
+// Text an operator acts on: the one covered by a motion or whole lines, as
+// in `dd`, `>>` or `gUU`.
+#[derive(Clone, Copy)]
+pub enum OperatorTarget {
+    Motion(Motion),
+    Lines,
+}
+
+// Normal mode command modifying the text.
+#[derive(Clone, Copy)]
+pub enum Command {
+    Operator {
+        op: Operator,
+        target: OperatorTarget,
+    },
+    DeleteChars,
+    Put {
+        before: bool,
+    },
+    // `i`, `a`, `I`, `A` or `o`.
+    Insert {
+        key: char,
+    },
+}
+
+// Change that is replayed by `.`, together with the keys typed in insert
+// mode if the command entered it.
+#[derive(Clone)]
+pub struct RepeatableChange {
+    pub command: Command,
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    pub inserted: Vec<Key>,
+}
@@ -0,0 +1,535 @@
+// Text storage of the editor. The text is kept in a balanced (AVL) binary
+// tree whose leaves hold chunks of at most MAX_LEAF bytes, so that edits
+// and line lookups cost O(log n) instead of shifting the whole file.
+//
+// Lines are separated by '\n' and the final newline of the file is not
+// stored, so there is always at least one (possibly empty) line.
+
+const MAX_LEAF: usize = 1024;
+
+enum Node {
+    Leaf {
+        text: String,
+        newlines: usize,
+    },
+    Branch {
+        left: Box<Node>,
+        right: Box<Node>,
+        len: usize,
+        newlines: usize,
+        height: usize,
+    },
+}
+
+impl Node {
+    fn leaf(text: String) -> Node {
+        let newlines = text.matches('\n').count();
+        Node::Leaf { text, newlines }
+    }
+
+    fn empty() -> Node {
+        Node::leaf(String::new())
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf { text, .. } => text.len(),
+            Node::Branch { len, .. } => *len,
+        }
+    }
+
+    fn newlines(&self) -> usize {
+        match self {
+            Node::Leaf { newlines, .. } => *newlines,
+            Node::Branch { newlines, .. } => *newlines,
+        }
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => 0,
+            Node::Branch { height, .. } => *height,
+        }
+    }
+
+    fn into_children(self) -> (Node, Node) {
+        match self {
+            Node::Branch { left, right, .. } => (*left, *right),
+            Node::Leaf { .. } => panic!("internal error"),
+        }
+    }
+
+    // Inserts `s` when it fits into the leaf containing `at`. Returns false
+    // without modifying anything otherwise.
+    fn insert_in_leaf(&mut self, at: usize, s: &str) -> bool {
+        match self {
+            Node::Leaf { text, newlines } => {
+                if text.len() + s.len() > MAX_LEAF {
+                    return false;
+                }
+                text.insert_str(at, s);
+                *newlines += s.matches('\n').count();
+                true
+            }
+            Node::Branch {
+                left,
+                right,
+                len,
+                newlines,
+                ..
+            } => {
+                let inserted = if at <= left.len() {
+                    left.insert_in_leaf(at, s)
+                } else {
+                    right.insert_in_leaf(at - left.len(), s)
+                };
+                if inserted {
+                    *len += s.len();
+                    *newlines += s.matches('\n').count();
+                }
+                inserted
+            }
+        }
+    }
+
+    // Removes the range when it lies within a single leaf. Returns false
+    // without modifying anything otherwise.
+    fn remove_in_leaf(&mut self, from: usize, to: usize) -> bool {
+        match self {
+            Node::Leaf { text, newlines } => {
+                *newlines -= text[from..to].matches('\n').count();
+                text.replace_range(from..to, "");
+                true
+            }
+            Node::Branch {
+                left,
+                right,
+                len,
+                newlines,
+                ..
+            } => {
+                let left_len = left.len();
+                let removed_newlines;
+                if to <= left_len {
+                    removed_newlines = left.newlines();
+                    if !left.remove_in_leaf(from, to) {
+                        return false;
+                    }
+                    *newlines -= removed_newlines - left.newlines();
+                } else if from >= left_len {
+                    removed_newlines = right.newlines();
+                    if !right.remove_in_leaf(from - left_len, to - left_len) {
+                        return false;
+                    }
+                    *newlines -= removed_newlines - right.newlines();
+                } else {
+                    return false;
+                }
+                *len -= to - from;
+                true
+            }
+        }
+    }
+
+    fn line_to_byte(&self, y: usize) -> usize {
+        match self {
+            Node::Leaf { text, .. } => {
+                if y == 0 {
+                    0
+                } else {
+                    text.match_indices('\n').nth(y - 1).unwrap().0 + 1
+                }
+            }
+            Node::Branch { left, right, .. } => {
+                if y <= left.newlines() {
+                    left.line_to_byte(y)
+                } else {
+                    left.len() + right.line_to_byte(y - left.newlines())
+                }
+            }
+        }
+    }
+
+    fn byte_to_line(&self, at: usize) -> usize {
+        match self {
+            Node::Leaf { text, .. } => text[..at].matches('\n').count(),
+            Node::Branch { left, right, .. } => {
+                if at <= left.len() {
+                    left.byte_to_line(at)
+                } else {
+                    left.newlines() + right.byte_to_line(at - left.len())
+                }
+            }
+        }
+    }
+
+    fn push_slice(&self, from: usize, to: usize, out: &mut String) {
+        match self {
+            Node::Leaf { text, .. } => out.push_str(&text[from..to]),
+            Node::Branch { left, right, .. } => {
+                let left_len = left.len();
+                if from < left_len {
+                    left.push_slice(from, to.min(left_len), out);
+                }
+                if to > left_len {
+                    right.push_slice(from.saturating_sub(left_len), to - left_len, out);
+                }
+            }
+        }
+    }
+}
+
+fn branch(left: Node, right: Node) -> Node {
+    Node::Branch {
+        len: left.len() + right.len(),
+        newlines: left.newlines() + right.newlines(),
+        height: left.height().max(right.height()) + 1,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+// Creates a branch from subtrees whose heights differ by at most two,
+// rotating it back into balance when needed.
+fn rebalance(left: Node, right: Node) -> Node {
+    let (hl, hr) = (left.height(), right.height());
+
+    if hl > hr + 2 || hr > hl + 2 {
+        join(left, right)
+    } else if hl > hr + 1 {
+        let (ll, lr) = left.into_children();
+        if lr.height() > ll.height() {
+            let (lrl, lrr) = lr.into_children();
+            branch(branch(ll, lrl), branch(lrr, right))
+        } else {
+            branch(ll, branch(lr, right))
+        }
+    } else if hr > hl + 1 {
+        let (rl, rr) = right.into_children();
+        if rl.height() > rr.height() {
+            let (rll, rlr) = rl.into_children();
+            branch(branch(left, rll), branch(rlr, rr))
+        } else {
+            branch(branch(left, rl), rr)
+        }
+    } else {
+        branch(left, right)
+    }
+}
+
+// Concatenates two trees of arbitrary heights.
+fn join(left: Node, right: Node) -> Node {
+    if left.len() == 0 {
+        return right;
+    }
+    if right.len() == 0 {
+        return left;
+    }
+
+    if let (Node::Leaf { text: a, .. }, Node::Leaf { text: b, .. }) = (&left, &right) {
+        if a.len() + b.len() <= MAX_LEAF {
+            return Node::leaf(a.clone() + b);
+        }
+    }
+
+    let (hl, hr) = (left.height(), right.height());
+    if hl > hr + 1 {
+        let (ll, lr) = left.into_children();
+        rebalance(ll, join(lr, right))
+    } else if hr > hl + 1 {
+        let (rl, rr) = right.into_children();
+        rebalance(join(left, rl), rr)
+    } else {
+        branch(left, right)
+    }
+}
+
+fn split(node: Node, at: usize) -> (Node, Node) {
+    match node {
+        Node::Leaf { mut text, .. } => {
+            let tail = text.split_off(at);
+            (Node::leaf(text), Node::leaf(tail))
+        }
+        Node::Branch { left, right, .. } => {
+            let left_len = left.len();
+            if at < left_len {
+                let (a, b) = split(*left, at);
+                (a, join(b, *right))
+            } else if at > left_len {
+                let (a, b) = split(*right, at - left_len);
+                (join(*left, a), b)
+            } else {
+                (*left, *right)
+            }
+        }
+    }
+}
+
+fn build(text: &str) -> Node {
+    let mut leaves = vec![];
+    let mut rest = text;
+    while rest.len() > MAX_LEAF {
+        let mut at = MAX_LEAF;
+        while !rest.is_char_boundary(at) {
+            at -= 1;
+        }
+        leaves.push(Node::leaf(rest[..at].to_string()));
+        rest = &rest[at..];
+    }
+    leaves.push(Node::leaf(rest.to_string()));
+
+    build_from_leaves(leaves)
+}
+
+fn build_from_leaves(mut leaves: Vec<Node>) -> Node {
+    if leaves.len() == 1 {
+        return leaves.pop().unwrap();
+    }
+    let right = leaves.split_off(leaves.len() / 2);
+    branch(build_from_leaves(leaves), build_from_leaves(right))
+}
+
+pub struct Rope {
+    root: Node,
+}
+
+impl Rope {
+    pub fn from_str(text: &str) -> Self {
+        Self { root: build(text) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn num_lines(&self) -> usize {
+        self.root.newlines() + 1
+    }
+
+    // Byte offset of the first character of line `y`.
+    pub fn line_to_byte(&self, y: usize) -> usize {
+        assert!(y < self.num_lines());
+        self.root.line_to_byte(y)
+    }
+
+    pub fn byte_to_line(&self, at: usize) -> usize {
+        self.root.byte_to_line(at)
+    }
+
+    // Length of line `y` in bytes, without the newline.
+    pub fn line_len(&self, y: usize) -> usize {
+        self.line_end(y) - self.line_to_byte(y)
+    }
+
+    pub fn line(&self, y: usize) -> String {
+        self.slice(self.line_to_byte(y), self.line_end(y))
+    }
+
+    fn line_end(&self, y: usize) -> usize {
+        if y + 1 < self.num_lines() {
+            self.line_to_byte(y + 1) - 1
+        } else {
+            self.len()
+        }
+    }
+
+    pub fn slice(&self, from: usize, to: usize) -> String {
+        let mut res = String::with_capacity(to - from);
+        if from < to {
+            self.root.push_slice(from, to, &mut res);
+        }
+        res
+    }
+
+    pub fn insert(&mut self, at: usize, text: &str) {
+        if text.is_empty() || self.root.insert_in_leaf(at, text) {
+            return;
+        }
+
+        let root = std::mem::replace(&mut self.root, Node::empty());
+        let (left, right) = split(root, at);
+        self.root = join(join(left, build(text)), right);
+    }
+
+    pub fn remove(&mut self, from: usize, to: usize) {
+        if from >= to || self.root.remove_in_leaf(from, to) {
+            return;
+        }
+
+        let root = std::mem::replace(&mut self.root, Node::empty());
+        let (left, rest) = split(root, from);
+        let (_, right) = split(rest, to - from);
+        self.root = join(left, right);
+    }
+
+    // Leaves of the tree in order. Useful for writing the text out without
+    // building a single string first.
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            stack: vec![&self.root],
+        }
+    }
+}
+
+pub struct Chunks<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            match self.stack.pop()? {
+                Node::Leaf { text, .. } => return Some(text),
+                Node::Branch { left, right, .. } => {
+                    self.stack.push(right);
+                    self.stack.push(left);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Xorshift generator, enough to drive the edits reproducibly.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn text(&mut self, max_len: usize) -> String {
+            let pieces = ["a", "bc", "\n", "é", "日本", "\n\n", " "];
+            let len = self.below(max_len + 1);
+            let mut text = String::new();
+            while text.len() < len {
+                text.push_str(pieces[self.below(pieces.len())]);
+            }
+            text
+        }
+
+        // Character boundary of `s`, the end included.
+        fn boundary(&mut self, s: &str) -> usize {
+            let mut at = self.below(s.len() + 1);
+            while !s.is_char_boundary(at) {
+                at -= 1;
+            }
+            at
+        }
+    }
+
+    // Checks the cached lengths and heights, the AVL balance and the size
+    // of the leaves. Returns the height.
+    fn check_node(node: &Node) -> usize {
+        match node {
+            Node::Leaf { text, newlines } => {
+                assert!(text.len() <= MAX_LEAF);
+                assert_eq!(*newlines, text.matches('\n').count());
+                0
+            }
+            Node::Branch {
+                left,
+                right,
+                len,
+                newlines,
+                height,
+            } => {
+                let (hl, hr) = (check_node(left), check_node(right));
+                assert!(hl.abs_diff(hr) <= 1, "unbalanced: {} and {}", hl, hr);
+                assert_eq!(*height, hl.max(hr) + 1);
+                assert_eq!(*len, left.len() + right.len());
+                assert_eq!(*newlines, left.newlines() + right.newlines());
+                *height
+            }
+        }
+    }
+
+    // Compares the rope with the text, and the index of every `step` line.
+    fn check(rope: &Rope, expected: &str, step: usize) {
+        check_node(&rope.root);
+        assert_eq!(rope.chunks().collect::<String>(), expected);
+        assert_eq!(rope.len(), expected.len());
+
+        let lines: Vec<&str> = expected.split('\n').collect();
+        assert_eq!(rope.num_lines(), lines.len());
+        let mut start = 0;
+        for (y, line) in lines.iter().enumerate() {
+            if y % step == 0 {
+                assert_eq!(rope.line_to_byte(y), start);
+                assert_eq!(rope.byte_to_line(start), y);
+                assert_eq!(rope.line_len(y), line.len());
+                assert_eq!(rope.line(y), *line);
+            }
+            start += line.len() + 1;
+        }
+    }
+
+    #[test]
+    fn random_edits_match_a_string() {
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e3779b97f4a7c15));
+            let mut expected = rng.text(3000);
+            let mut rope = Rope::from_str(&expected);
+            check(&rope, &expected, 1);
+
+            for _ in 0..200 {
+                if rng.below(2) == 0 {
+                    let at = rng.boundary(&expected);
+                    let max_len = if rng.below(4) == 0 { 3000 } else { 20 };
+                    let text = rng.text(max_len);
+                    rope.insert(at, &text);
+                    expected.insert_str(at, &text);
+                } else {
+                    let from = rng.boundary(&expected);
+                    let mut to = (from + rng.below(2000)).min(expected.len());
+                    while !expected.is_char_boundary(to) {
+                        to -= 1;
+                    }
+                    rope.remove(from, to);
+                    expected.replace_range(from..to, "");
+                }
+                check(&rope, &expected, 97);
+
+                let from = rng.boundary(&expected);
+                let to = from + rng.boundary(&expected[from..]);
+                assert_eq!(rope.slice(from, to), expected[from..to]);
+            }
+            check(&rope, &expected, 1);
+        }
+    }
+
+    #[test]
+    fn large_text_is_balanced() {
+        let line = "x".repeat(99) + "\n";
+        let expected = line.repeat(10_000);
+        let mut rope = Rope::from_str(&expected);
+        check(&rope, &expected, 1);
+
+        // Removing most of the text joins subtrees of very different
+        // heights.
+        rope.remove(1000, expected.len() - 1000);
+        check(
+            &rope,
+            &(expected[..1000].to_string() + &expected[expected.len() - 1000..]),
+            1,
+        );
+    }
+
+    #[test]
+    fn empty_text_has_one_line() {
+        let mut rope = Rope::from_str("");
+        check(&rope, "", 1);
+        rope.insert(0, "\n");
+        check(&rope, "\n", 1);
+        rope.remove(0, 1);
+        check(&rope, "", 1);
+    }
+}
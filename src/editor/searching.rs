@@ -1,59 +1,326 @@
+use crate::editor::ex;
+use crate::editor::regex::{self, Regex};
 use crate::editor::Editor;
+use std::rc::Rc;
 
-pub fn forward_search(editor: &mut Editor, pattern: &str) -> bool {
-    for i in 0..editor.lines.len() {
-        let y = (editor.cursor.fy + i) % editor.lines.len();
+// Matches counted after a search, like Vim's default `maxcount`. More are
+// shown as ">99".
+pub const MAX_COUNT: usize = 99;
 
-        if let Some(x) = search_forward_in_line(
-            editor,
-            y,
-            if i == 0 { editor.cursor.fx } else { 0 },
-            pattern,
-        ) {
-            editor.offset.y = y.saturating_sub(10);
-            editor.cursor.fy = y;
-            editor.cursor.fx = x;
-            return true;
+// Whether a search for the pattern ignores case, following the
+// `ignorecase` and `smartcase` options. `\c` and `\C` in the pattern take
+// precedence.
+pub fn ignore_case(editor: &Editor, pattern: &str) -> bool {
+    editor.options.ignorecase() && !(editor.options.smartcase() && regex::has_uppercase(pattern))
+}
+
+pub fn regex(editor: &Editor, pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern, ignore_case(editor, pattern))
+}
+
+// Regex of the last pattern, compiled again only when the pattern or the
+// case options change since the matches are highlighted on every redraw.
+pub fn last_regex(editor: &mut Editor) -> Option<Rc<Regex>> {
+    let pattern = editor.last_pattern.as_ref()?;
+    let ignore_case = ignore_case(editor, pattern);
+
+    match &editor.last_regex {
+        Some((cached, cached_ignore_case, regex))
+            if cached == pattern && *cached_ignore_case == ignore_case =>
+        {
+            Some(regex.clone())
+        }
+        _ => {
+            let regex = Rc::new(Regex::new(pattern, ignore_case).ok()?);
+            editor.last_regex = Some((pattern.clone(), ignore_case, regex.clone()));
+            Some(regex)
         }
     }
+}
 
-    false
+// Where the cursor lands relative to a match, as in `/foo/e+1`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Offset {
+    // Lines below the match, in the first column.
+    Lines(i64),
+    // Characters after the start of the match.
+    Start(i64),
+    // Characters after the last character of the match.
+    End(i64),
 }
 
-pub fn backward_search(editor: &mut Editor, pattern: &str) -> bool {
-    let len = editor.lines.len();
+impl Default for Offset {
+    fn default() -> Self {
+        Offset::Start(0)
+    }
+}
 
-    for i in 0..len {
-        let y = (editor.cursor.fy + len - i) % len;
+pub struct Search {
+    pub pattern: String,
+    pub forward: bool,
+    pub offset: Offset,
+}
 
-        if let Some(x) = search_backward_in_line(
-            editor,
-            y,
-            if i == 0 {
-                editor.cursor.fx
-            } else {
-                editor.lines[y].content.len()
-            },
+// Parses what follows `/` or `?`, like `foo/e+1` or `foo/;?bar`. An empty
+// input repeats the last search in the given direction.
+pub fn parse(editor: &Editor, input: &str, forward: bool) -> Result<Vec<Search>, String> {
+    let last_pattern = || {
+        editor
+            .last_pattern
+            .clone()
+            .ok_or("No previous regular expression".to_string())
+    };
+
+    if input.is_empty() {
+        return Ok(vec![Search {
+            pattern: last_pattern()?,
+            forward,
+            offset: editor.search_offset,
+        }]);
+    }
+
+    let mut searches = vec![];
+    let mut s = input;
+    let mut forward = forward;
+
+    loop {
+        let pattern = match ex::parse_pattern(&mut s, if forward { '/' } else { '?' }) {
+            pattern if pattern.is_empty() => last_pattern()?,
+            pattern => pattern,
+        };
+
+        let len = s.find(';').unwrap_or(s.len());
+        let offset = parse_offset(&s[..len])?;
+        s = &s[len..];
+
+        searches.push(Search {
             pattern,
-        ) {
-            editor.offset.y = y.saturating_sub(10);
-            editor.cursor.fy = y;
-            editor.cursor.fx = x;
-            return true;
+            forward,
+            offset,
+        });
+
+        match s.strip_prefix(';') {
+            None => return Ok(searches),
+            Some(rest) => match rest.chars().next() {
+                Some(c @ ('/' | '?')) => {
+                    forward = c == '/';
+                    s = &rest[1..];
+                }
+                _ => return Err(format!("Expected / or ? after ;: {}", input)),
+            },
         }
     }
+}
+
+fn parse_offset(s: &str) -> Result<Offset, String> {
+    let error = || format!("Invalid search offset: {}", s);
 
-    false
+    let (kind, amount) = match s.chars().next() {
+        Some(c @ ('e' | 's' | 'b')) => (Some(c), &s[1..]),
+        _ => (None, s),
+    };
+    let n = match amount {
+        "" => 0,
+        "+" => 1,
+        "-" => -1,
+        amount => amount
+            .strip_prefix('+')
+            .unwrap_or(amount)
+            .parse()
+            .map_err(|_| error())?,
+    };
+
+    Ok(match kind {
+        Some('e') => Offset::End(n),
+        Some(_) => Offset::Start(n),
+        None if amount.is_empty() => Offset::default(),
+        None => Offset::Lines(n),
+    })
+}
+
+// Bytes of a line matched by a pattern, together with the parts matched by
+// its groups.
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub groups: Vec<Option<(usize, usize)>>,
 }
 
-fn search_forward_in_line(editor: &Editor, y: usize, x: usize, pattern: &str) -> Option<usize> {
-    if let Some(x1) = editor.lines[y].content[x..].find(pattern) {
-        Some(x1 + x)
+// First match of the regex in the line starting at `from` or after it.
+pub fn find_match(line: &str, from: usize, regex: &Regex) -> Option<Match> {
+    let mut spans = regex.find_at(line, from)?.into_iter();
+    let (start, end) = spans.next()??;
+
+    Some(Match {
+        start,
+        end,
+        groups: spans.collect(),
+    })
+}
+
+// All the matches in the line, an empty match never at the same place as
+// the one before.
+pub fn line_matches(line: &str, regex: &Regex) -> Vec<Match> {
+    let mut matches = vec![];
+    let mut from = 0;
+
+    while let Some(m) = find_match(line, from, regex) {
+        from = if m.start == m.end {
+            match line[m.end..].chars().next() {
+                Some(c) => m.end + c.len_utf8(),
+                None => line.len() + 1,
+            }
+        } else {
+            m.end
+        };
+        matches.push(m);
+        if from > line.len() {
+            break;
+        }
+    }
+
+    matches
+}
+
+// Moves `x` by `n` characters within the line.
+fn move_chars(line: &str, x: usize, n: i64) -> usize {
+    let mut x = x.min(line.len());
+    for _ in 0..n.unsigned_abs() {
+        let c = if n > 0 {
+            line[x..].chars().next()
+        } else {
+            line[..x].chars().next_back()
+        };
+        match c {
+            Some(c) if n > 0 && x + c.len_utf8() < line.len() => x += c.len_utf8(),
+            Some(c) if n < 0 => x -= c.len_utf8(),
+            _ => break,
+        }
+    }
+    x
+}
+
+// Position of the cursor for a match with the offset applied.
+fn landing(editor: &Editor, y: usize, m: &Match, offset: Offset) -> (usize, usize) {
+    let line = editor.buffer.text.line(y);
+    match offset {
+        Offset::Lines(n) => {
+            let y = (y as i64 + n).clamp(0, editor.buffer.text.num_lines() as i64 - 1);
+            (y as usize, 0)
+        }
+        Offset::Start(n) => (y, move_chars(&line, m.start, n)),
+        Offset::End(n) => {
+            let last = match line[..m.end].chars().next_back() {
+                Some(c) if m.end > m.start => m.end - c.len_utf8(),
+                _ => m.start,
+            };
+            (y, move_chars(&line, last, n))
+        }
+    }
+}
+
+// Cursor position after the search, wrapping around the end of the text
+// with `wrapscan`. The match is the first one landing after the cursor, or
+// before it when searching backwards.
+fn find(editor: &Editor, regex: &Regex, forward: bool, offset: Offset) -> Option<(usize, usize)> {
+    let len = editor.buffer.text.num_lines();
+    let cursor = (editor.cursor.fy, editor.cursor.fx);
+
+    // Matches on earlier lines may land on the cursor line.
+    let shift = match offset {
+        Offset::Lines(n) => n,
+        _ => 0,
+    };
+    let first = (cursor.0 as i64 - shift).clamp(0, len as i64 - 1) as usize;
+
+    let landings = |y: usize| -> Vec<(usize, usize)> {
+        line_matches(&editor.buffer.text.line(y), regex)
+            .iter()
+            .map(|m| landing(editor, y, m, offset))
+            .collect()
+    };
+
+    let wrapscan = editor.options.wrapscan();
+    if forward {
+        (first..len)
+            .flat_map(landings)
+            .find(|&pos| pos > cursor)
+            .or_else(|| wrapscan.then(|| (0..len).flat_map(landings).next())?)
     } else {
-        None
+        (0..=first)
+            .rev()
+            .flat_map(|y| landings(y).into_iter().rev())
+            .find(|&pos| pos < cursor)
+            .or_else(|| {
+                wrapscan.then(|| {
+                    (0..len)
+                        .rev()
+                        .flat_map(|y| landings(y).into_iter().rev())
+                        .next()
+                })?
+            })
+    }
+}
+
+// Number of matches up to the cursor and in the whole text. Counting stops
+// after MAX_COUNT matches, so that a search in a big file stays fast.
+pub fn count_matches(editor: &Editor, pattern: &str) -> Option<(usize, usize)> {
+    let regex = regex(editor, pattern).ok()?;
+    let cursor = (editor.cursor.fy, editor.cursor.fx);
+    let (mut index, mut total) = (0, 0);
+
+    for y in 0..editor.buffer.text.num_lines() {
+        for m in line_matches(&editor.buffer.text.line(y), &regex) {
+            total += 1;
+            if (y, m.start) <= cursor {
+                index += 1;
+            }
+            if total > MAX_COUNT {
+                return Some((index.max(1), total));
+            }
+        }
     }
+
+    Some((index.max(1), total))
 }
 
-fn search_backward_in_line(editor: &Editor, y: usize, x: usize, pattern: &str) -> Option<usize> {
-    editor.lines[y].content[0..x].find(pattern)
+// Moves the cursor to the next match of the search.
+pub fn search(editor: &mut Editor, search: &Search) -> Result<(), String> {
+    let regex = regex(editor, &search.pattern)?;
+    let (y, x) = find(editor, &regex, search.forward, search.offset).ok_or_else(|| {
+        let elsewhere = (0..editor.buffer.text.num_lines())
+            .any(|y| find_match(&editor.buffer.text.line(y), 0, &regex).is_some());
+        match (elsewhere, search.forward) {
+            (false, _) => format!("Pattern not found: {}", search.pattern),
+            (true, true) => format!("Search hit BOTTOM without match for: {}", search.pattern),
+            (true, false) => format!("Search hit TOP without match for: {}", search.pattern),
+        }
+    })?;
+
+    editor.offset.y = y.saturating_sub(10);
+    editor.cursor.fy = y;
+    editor.cursor.fx = x;
+    Ok(())
+}
+
+// Line matching the pattern after (or before) the line `y`, wrapping
+// around the end of the text.
+pub fn find_line(
+    editor: &Editor,
+    y: usize,
+    pattern: &str,
+    forward: bool,
+) -> Result<Option<usize>, String> {
+    let regex = regex(editor, pattern)?;
+    let len = editor.buffer.text.num_lines();
+
+    Ok((1..=len)
+        .map(|i| {
+            if forward {
+                (y + i) % len
+            } else {
+                (y + len - i) % len
+            }
+        })
+        .find(|&y| find_match(&editor.buffer.text.line(y), 0, &regex).is_some()))
 }
@@ -0,0 +1,364 @@
+use super::ex;
+use super::regex::Regex;
+use super::searching::{self, Match};
+use super::{motions, Editor};
+use crate::terminal::input::Key;
+
+#[derive(Clone, Copy, Default)]
+pub struct Flags {
+    pub global: bool,
+    // Overrides the `ignorecase` option when given.
+    pub ignore_case: Option<bool>,
+    pub confirm: bool,
+}
+
+#[derive(Clone)]
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: Flags,
+}
+
+// Parses the argument of `:s`, like `/pat/rep/gi`. Without a pattern, as in
+// `:s g` or `:&&`, the last substitution is repeated with new flags.
+pub fn parse(editor: &Editor, arg: &str) -> Result<Substitute, String> {
+    let last = editor.last_substitute.as_ref();
+
+    let delimiter = match arg.chars().next() {
+        Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|&".contains(c) => c,
+        _ => {
+            let last = last.ok_or("No previous substitute regular expression")?;
+            return Ok(Substitute {
+                flags: parse_flags(arg, &last.flags)?,
+                ..last.clone()
+            });
+        }
+    };
+
+    let mut s = &arg[delimiter.len_utf8()..];
+    let pattern = match ex::parse_pattern(&mut s, delimiter) {
+        pattern if pattern.is_empty() => editor
+            .last_pattern
+            .clone()
+            .ok_or("No previous regular expression")?,
+        pattern => pattern,
+    };
+
+    let replacement = expand_tilde(
+        &ex::parse_pattern(&mut s, delimiter),
+        last.map_or("", |last| &last.replacement),
+    );
+
+    Ok(Substitute {
+        pattern,
+        replacement,
+        flags: parse_flags(s, &Flags::default())?,
+    })
+}
+
+// `~` in a replacement stands for the previous one.
+fn expand_tilde(raw_replacement: &str, previous: &str) -> String {
+    let mut replacement = String::new();
+    let mut chars = raw_replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                replacement.push(c);
+                replacement.extend(chars.next());
+            }
+            '~' => replacement += previous,
+            c => replacement.push(c),
+        }
+    }
+    replacement
+}
+
+// Flags after the replacement. A leading `&` keeps the flags of the last
+// substitution.
+fn parse_flags(s: &str, last: &Flags) -> Result<Flags, String> {
+    let mut flags = Flags::default();
+
+    for (i, c) in s.trim().chars().enumerate() {
+        match c {
+            '&' if i == 0 => flags = *last,
+            'g' => flags.global = true,
+            'i' => flags.ignore_case = Some(true),
+            'I' => flags.ignore_case = Some(false),
+            'c' => flags.confirm = true,
+            _ => return Err(format!("Trailing characters: {}", s.trim())),
+        }
+    }
+
+    Ok(flags)
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Unchanged,
+    Upper,
+    Lower,
+}
+
+fn push_with_case(result: &mut String, text: &str, next: &mut Case, following: Case) {
+    for c in text.chars() {
+        match std::mem::replace(next, Case::Unchanged) {
+            Case::Upper => result.extend(c.to_uppercase()),
+            Case::Lower => result.extend(c.to_lowercase()),
+            Case::Unchanged => match following {
+                Case::Upper => result.extend(c.to_uppercase()),
+                Case::Lower => result.extend(c.to_lowercase()),
+                Case::Unchanged => result.push(c),
+            },
+        }
+    }
+}
+
+// Text replacing a match. `&` and `\0` stand for the matched text, `\1` to
+// `\9` for the groups, `\u` and `\l` change the case of the next character,
+// `\U` and `\L` of the following ones up to `\E`, and `\r` breaks the line.
+fn expand_replacement(replacement: &str, line: &str, m: &Match) -> String {
+    let mut result = String::new();
+    let mut next = Case::Unchanged;
+    let mut following = Case::Unchanged;
+
+    let group = |i: usize| -> &str {
+        if i == 0 {
+            return &line[m.start..m.end];
+        }
+        match m.groups.get(i - 1) {
+            Some(&Some((start, end))) => &line[start..end],
+            _ => "",
+        }
+    };
+
+    let mut buf = [0; 4];
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        let text: &str = match c {
+            '&' => group(0),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => group(d.to_digit(10).unwrap() as usize),
+                Some('u') => {
+                    next = Case::Upper;
+                    continue;
+                }
+                Some('l') => {
+                    next = Case::Lower;
+                    continue;
+                }
+                Some('U') => {
+                    following = Case::Upper;
+                    continue;
+                }
+                Some('L') => {
+                    following = Case::Lower;
+                    continue;
+                }
+                Some('E' | 'e') => {
+                    following = Case::Unchanged;
+                    continue;
+                }
+                Some('r' | 'n') => {
+                    result.push('\n');
+                    continue;
+                }
+                Some('t') => "\t",
+                Some(c) => c.encode_utf8(&mut buf),
+                None => "\\",
+            },
+            c => c.encode_utf8(&mut buf),
+        };
+        push_with_case(&mut result, text, &mut next, following);
+    }
+
+    result
+}
+
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+    Last,
+}
+
+// Shows the match and asks whether to replace it.
+fn ask(editor: &mut Editor, y: usize, m: &Match, replacement: &str) -> Answer {
+    editor.current_match = Some((y, m.start, m.end));
+    editor.cursor.fy = y;
+    editor.cursor.fx = m.start;
+    editor.cmd_message = format!("replace with {} (y/n/a/q/l)?", replacement);
+
+    let answer = loop {
+        editor.refresh_screen();
+        match editor.read_key() {
+            Key::Char('y') => break Answer::Yes,
+            Key::Char('n') => break Answer::No,
+            Key::Char('a') => break Answer::All,
+            Key::Char('q') | Key::Escape => break Answer::Quit,
+            Key::Char('l') => break Answer::Last,
+            _ => {}
+        }
+    };
+
+    editor.current_match = None;
+    editor.cmd_message.clear();
+    answer
+}
+
+// Replaces the matches of the pattern in the lines from `first` to `last`
+// and reports how many were replaced.
+pub fn execute(editor: &mut Editor, substitute: Substitute, (first, last): (usize, usize)) {
+    editor.last_pattern = Some(substitute.pattern.clone());
+    editor.last_substitute = Some(substitute.clone());
+    editor.highlight_matches = true;
+
+    let flags = substitute.flags;
+    let ignore_case = flags
+        .ignore_case
+        .unwrap_or_else(|| searching::ignore_case(editor, &substitute.pattern));
+    let regex = match Regex::new(&substitute.pattern, ignore_case) {
+        Ok(regex) => regex,
+        Err(message) => {
+            editor.cmd_message = message;
+            editor.fail();
+            return;
+        }
+    };
+
+    let mut confirm = flags.confirm;
+    let mut num_substitutions = 0;
+    let mut num_lines = 0;
+    let mut last_changed = None;
+    let mut quit = false;
+
+    let (mut y, mut last) = (first, last);
+    while y <= last && !quit {
+        // The matches are all found in the line as it is, then replaced
+        // from the last one, so that the earlier ones keep their place and
+        // no match is found in a replacement.
+        let line = editor.buffer.text.line(y);
+        let mut matches = searching::line_matches(&line, &regex);
+        if !flags.global {
+            matches.truncate(1);
+        }
+
+        let mut accepted = vec![];
+        for m in matches {
+            let answer = if confirm {
+                ask(editor, y, &m, &substitute.replacement)
+            } else {
+                Answer::Yes
+            };
+
+            match answer {
+                Answer::Quit => quit = true,
+                Answer::No => {}
+                Answer::Yes => accepted.push(m),
+                Answer::All => {
+                    confirm = false;
+                    accepted.push(m);
+                }
+                Answer::Last => {
+                    quit = true;
+                    accepted.push(m);
+                }
+            }
+            if quit {
+                break;
+            }
+        }
+
+        let mut num_breaks = 0;
+        for m in accepted.iter().rev() {
+            let text = expand_replacement(&substitute.replacement, &line, m);
+            editor.delete_text((y, m.start), (y, m.end));
+            editor.insert_text(y, m.start, &text);
+            num_breaks += text.matches('\n').count();
+        }
+
+        if !accepted.is_empty() {
+            num_substitutions += accepted.len();
+            num_lines += 1;
+            // The replacements may break the line.
+            y += num_breaks;
+            last += num_breaks;
+            last_changed = Some(y);
+        }
+        y += 1;
+    }
+
+    match last_changed {
+        Some(y) => {
+            editor.cursor.fy = y;
+            editor.cursor.fx = motions::first_non_blank(editor, y);
+            editor.cmd_message = format!(
+                "{} substitution{} on {} line{}",
+                num_substitutions,
+                if num_substitutions == 1 { "" } else { "s" },
+                num_lines,
+                if num_lines == 1 { "" } else { "s" }
+            );
+        }
+        None if !quit => {
+            editor.cmd_message = format!("Pattern not found: {}", substitute.pattern);
+            editor.fail();
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replacement of the first match of `pattern` in `line`.
+    fn expand(pattern: &str, line: &str, replacement: &str) -> String {
+        let regex = Regex::new(pattern, false).unwrap();
+        let m = searching::find_match(line, 0, &regex).unwrap();
+        expand_replacement(replacement, line, &m)
+    }
+
+    #[test]
+    fn matched_text_and_groups() {
+        assert_eq!(expand("b.", "abcd", "[&]"), "[bc]");
+        assert_eq!(expand("b.", "abcd", "[\\0]"), "[bc]");
+        assert_eq!(expand("\\(a\\)\\(b\\)", "ab", "\\2\\1"), "ba");
+        assert_eq!(
+            expand("\\(a\\)\\|\\(b\\)", "b", "<\\1><\\2><\\9>"),
+            "<><b><>"
+        );
+        assert_eq!(expand("a", "a", "\\&\\\\x~"), "&\\x~");
+    }
+
+    #[test]
+    fn case_changes() {
+        assert_eq!(expand("\\w\\+", "hello", "\\u&"), "Hello");
+        assert_eq!(expand("\\w\\+", "HELLO", "\\l&"), "hELLO");
+        assert_eq!(expand("\\w\\+", "hello", "\\U&\\E!"), "HELLO!");
+        assert_eq!(expand("\\w\\+", "HeLLo", "\\L&-\\Eok"), "hello-ok");
+        assert_eq!(expand("\\w\\+", "hello", "\\U&\\ex"), "HELLOx");
+        // `\u` and `\l` win over `\U` and `\L` for the next character.
+        assert_eq!(expand("\\w\\+", "hello", "\\U\\l&"), "hELLO");
+        assert_eq!(expand("\\w\\+", "HELLO", "\\L\\u&"), "Hello");
+        assert_eq!(expand("ż", "żółw", "\\u&"), "Ż");
+    }
+
+    #[test]
+    fn special_characters() {
+        assert_eq!(expand(",", "a,b", "\\r"), "\n");
+        assert_eq!(expand(",", "a,b", "\\n"), "\n");
+        assert_eq!(expand(",", "a,b", "\\t"), "\t");
+        assert_eq!(expand(",", "a,b", "x\\"), "x\\");
+    }
+
+    #[test]
+    fn previous_replacement() {
+        assert_eq!(expand_tilde("<~>", "\\u&"), "<\\u&>");
+        assert_eq!(expand_tilde("~~", "ab"), "abab");
+        assert_eq!(expand_tilde("\\~~", "ab"), "\\~ab");
+        assert_eq!(expand_tilde("~", ""), "");
+        // The `~` taken literally in the replacement.
+        assert_eq!(expand(",", "a,b", &expand_tilde("\\~", "x")), "~");
+    }
+}
@@ -9,19 +9,38 @@ mod terminal;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("usage: {} <file>", args[0]);
+    let usage = || -> ! {
+        eprintln!("usage: {} [-u virurc|NONE] <file>...", args[0]);
         std::process::exit(1);
+    };
+
+    let mut virurc = editor::config::Virurc::Default;
+    let mut files = vec![];
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-u" => {
+                virurc = match rest.next().map(String::as_str) {
+                    Some("NONE") => editor::config::Virurc::None,
+                    Some(path) => editor::config::Virurc::File(path.to_string()),
+                    None => usage(),
+                }
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+    if files.is_empty() {
+        usage();
     }
 
     let fixer = terminal::settings::TerminalFixer::new();
     terminal::settings::enable_row_mode();
 
-    let mut editor = editor::Editor::new(args[1].clone())?;
+    let mut editor = editor::Editor::new(&files, virurc)?;
     loop {
         editor.refresh_screen();
 
-        if let Err(_) = editor.process_key_press() {
+        if editor.process_key_press().is_err() {
             break;
         }
     }
@@ -3,6 +3,8 @@ use std::io::{self, Write};
 pub struct TermBuffer {
     buffer: Vec<u8>,
     fg_color: u8,
+    bg_color: u8,
+    reverse_video: bool,
 }
 
 // Structure for minimizing terminal output operations
@@ -11,6 +13,8 @@ impl TermBuffer {
         Self {
             buffer: vec![],
             fg_color: 0,
+            bg_color: 49,
+            reverse_video: false,
         }
     }
 
@@ -19,7 +23,7 @@ impl TermBuffer {
     }
 
     pub fn flush(&mut self) {
-        io::stdout().write(&self.buffer).unwrap();
+        io::stdout().write_all(&self.buffer).unwrap();
         io::stdout().flush().unwrap();
         self.buffer.clear();
     }
@@ -52,12 +56,30 @@ impl TermBuffer {
 
     pub fn reset_appearance(&mut self) {
         self.graphic_rendition(0);
+        self.fg_color = 0;
+        self.bg_color = 49;
+        self.reverse_video = false;
     }
 
+    pub fn set_reverse_video(&mut self, on: bool) {
+        if self.reverse_video != on {
+            self.graphic_rendition(if on { 7 } else { 27 });
+            self.reverse_video = on;
+        }
+    }
+
+    // Color 0 stands for the default one.
     pub fn set_fg_color(&mut self, color: u8) {
         if self.fg_color != color {
-            self.graphic_rendition(color);
+            self.graphic_rendition(if color == 0 { 39 } else { color });
             self.fg_color = color;
         }
     }
+
+    pub fn set_bg_color(&mut self, color: u8) {
+        if self.bg_color != color {
+            self.graphic_rendition(color);
+            self.bg_color = color;
+        }
+    }
 }
@@ -1,9 +1,11 @@
 use std::io::{self, Read};
+use std::time::{Duration, Instant};
 
 fn ctrl_key(c: char) -> u8 {
     (c as u8) % 32
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum Key {
     Colon,
     Slash,
@@ -14,29 +16,157 @@ pub enum Key {
     Char(char),
 }
 
-pub fn read_key() -> Key {
+impl Key {
+    // Keys are stored in registers (e.g. by macro recording) as the bytes
+    // the terminal sends for them.
+    pub fn from_char(c: char) -> Key {
+        // C-M = Enter
+        if c != '\r' && (c as u32) < 32 {
+            if let Some(letter) = ('a'..='z').find(|&l| ctrl_key(l) as char == c) {
+                return Key::Control(letter);
+            }
+        }
+
+        match c {
+            '\x1B' => Key::Escape,
+            '\x7F' => Key::Backspace,
+            '\r' => Key::Enter,
+            '/' => Key::Slash,
+            ':' => Key::Colon,
+            c => Key::Char(c),
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Key::Colon => ':',
+            Key::Slash => '/',
+            Key::Enter => '\r',
+            Key::Escape => '\x1B',
+            Key::Backspace => '\x7F',
+            Key::Control(c) => ctrl_key(c) as char,
+            Key::Char(c) => c,
+        }
+    }
+}
+
+// Source of the keys processed by the editor.
+pub trait KeySource {
+    fn read_key(&mut self) -> Key;
+    // Gives up after the timeout, e.g. while waiting for the rest of a
+    // mapping.
+    fn read_key_timeout(&mut self, timeout: Duration) -> Option<Key>;
+}
+
+// Reads the keys typed in the terminal.
+#[derive(Default)]
+pub struct TerminalInput {
+    // Byte that cut a character short, which starts the next key.
+    pending: Option<u8>,
+}
+
+impl KeySource for TerminalInput {
+    fn read_key(&mut self) -> Key {
+        self.read_key_until(None).unwrap()
+    }
+
+    fn read_key_timeout(&mut self, timeout: Duration) -> Option<Key> {
+        self.read_key_until(Some(Instant::now() + timeout))
+    }
+}
+
+impl TerminalInput {
+    fn read_key_until(&mut self, deadline: Option<Instant>) -> Option<Key> {
+        let first = match self.pending.take() {
+            Some(byte) => byte,
+            None => read_byte(deadline)?,
+        };
+        let continuation_deadline = Instant::now() + Duration::from_millis(50);
+        let (key, pending) = decode_key(first, || read_byte(Some(continuation_deadline)));
+        self.pending = pending;
+        Some(key)
+    }
+}
+
+// Reads wait for a tenth of a second at most, see `enable_row_mode`.
+fn read_byte(deadline: Option<Instant>) -> Option<u8> {
     let mut buf: [u8; 1] = [0; 1];
 
     loop {
         let nread = io::stdin().read(&mut buf[..]).unwrap();
         if nread == 1 {
-            break;
+            return Some(buf[0]);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+    }
+}
+
+// A character typed in the terminal arrives as its UTF-8 bytes, which come
+// together. Malformed input, like a lone continuation byte or a sequence
+// cut short, gives the replacement character. A byte that cuts a sequence
+// short is returned too, as it starts the next key.
+fn decode_key(first: u8, mut next_byte: impl FnMut() -> Option<u8>) -> (Key, Option<u8>) {
+    let len = match first.leading_ones() {
+        0 => return (Key::from_char(first as char), None),
+        2..=4 => first.leading_ones() as usize,
+        _ => return (Key::Char(char::REPLACEMENT_CHARACTER), None),
+    };
+
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match next_byte() {
+            Some(byte) if byte & 0xC0 == 0x80 => bytes.push(byte),
+            other => return (Key::Char(char::REPLACEMENT_CHARACTER), other),
         }
     }
 
-    // C-M = Enter
-    for c in ('a'..='z').filter(|&c| c != 'm').collect::<Vec<char>>() {
-        if buf[0] == ctrl_key(c) {
-            return Key::Control(c);
+    let key = match std::str::from_utf8(&bytes) {
+        Ok(s) => Key::from_char(s.chars().next().unwrap()),
+        Err(_) => Key::Char(char::REPLACEMENT_CHARACTER),
+    };
+    (key, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes the keys of `input` the way the terminal input does.
+    fn decode_all(input: &[u8]) -> Vec<char> {
+        let mut bytes = input.iter().copied();
+        let mut pending = None;
+        let mut keys = vec![];
+        while let Some(first) = pending.take().or_else(|| bytes.next()) {
+            let (key, rest) = decode_key(first, || bytes.next());
+            keys.push(key.to_char());
+            pending = rest;
         }
+        keys
+    }
+
+    #[test]
+    fn characters() {
+        assert_eq!(decode_all(b"a:\r\x1B"), vec!['a', ':', '\r', '\x1B']);
+        assert_eq!(
+            decode_all("żółw €𝄞".as_bytes()),
+            "żółw €𝄞".chars().collect::<Vec<_>>()
+        );
     }
 
-    match buf[0] {
-        b'\x1B' => Key::Escape,
-        b'\x7F' => Key::Backspace,
-        b'\r' => Key::Enter,
-        b'/' => Key::Slash,
-        b':' => Key::Colon,
-        u => Key::Char(u as char),
+    #[test]
+    fn malformed_input() {
+        let r = char::REPLACEMENT_CHARACTER;
+        // Lone continuation bytes and invalid lead bytes.
+        assert_eq!(decode_all(b"\x80a\xFFb"), vec![r, 'a', r, 'b']);
+        // A sequence cut short by the end of the input.
+        assert_eq!(decode_all(b"a\xC5"), vec!['a', r]);
+        // A sequence cut short by another key, which is kept.
+        assert_eq!(decode_all(b"\xC5a"), vec![r, 'a']);
+        assert_eq!(decode_all(b"\xE2\x82\x1B"), vec![r, '\x1B']);
+        assert_eq!(decode_all(b"\xE2\xC5\xBC"), vec![r, 'ż']);
+        // Overlong encodings.
+        assert_eq!(decode_all(b"\xC0\xAFx"), vec![r, 'x']);
     }
 }
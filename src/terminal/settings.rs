@@ -5,8 +5,7 @@ use termios::*;
 
 fn get_terminal_settings() -> Termios {
     let stdout_fd = io::stdout().as_raw_fd();
-    let termios = Termios::from_fd(stdout_fd).unwrap();
-    return termios;
+    Termios::from_fd(stdout_fd).unwrap()
 }
 
 pub struct TerminalFixer {
@@ -63,7 +62,9 @@ pub fn get_window_size() -> Window {
 
     loop {
         assert!(len < buf.len());
-        io::stdin().read(&mut buf[len..=len]).unwrap();
+        if io::stdin().read(&mut buf[len..=len]).unwrap() == 0 {
+            continue;
+        }
 
         if buf[len] == b'R' {
             buf[len] = b'0';
@@ -81,5 +82,5 @@ pub fn get_window_size() -> Window {
     let num_rows = parts.next().unwrap().parse().unwrap();
     let num_cols = parts.next().unwrap().parse().unwrap();
 
-    return Window { num_rows, num_cols };
+    Window { num_rows, num_cols }
 }